@@ -0,0 +1,510 @@
+use crate::state::State;
+
+/// Operators, longer ones first so `<<=` isn't read as `<<` and `=`.
+const OPERATORS: [&str; 38] = [
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~",
+    "?", ":", "=", "(", ")",
+];
+
+const ASSIGNMENTS: [&str; 11] = [
+    "=", "+=", "-=", "*=", "/=", "%=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+/// Binary operators from the lowest precedence to the highest, all left associative.
+/// `**` binds tighter and is handled apart since it is right associative.
+const LEVELS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// How deep variables holding expressions may refer to each other.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+    Comma,
+}
+
+/// Evaluates shell arithmetic, as used by `$((...))`, `((...))` and `let`. Values
+/// are 64-bit signed integers that wrap around, variables may hold numbers or other
+/// expressions and are assigned with `=`, `+=` and the like.
+pub fn evaluate(expr: &str, state: &mut State) -> Result<i64, String> {
+    Arithmetic::evaluate(expr, state, 0)
+}
+
+struct Arithmetic<'a> {
+    expr: &'a str,
+    /// Tokens with the offset where each starts in `expr`.
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    state: &'a mut State,
+    depth: usize,
+}
+
+impl<'a> Arithmetic<'a> {
+    fn evaluate(expr: &'a str, state: &'a mut State, depth: usize) -> Result<i64, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!(
+                "{}: expression recursion level exceeded",
+                expr.trim()
+            ));
+        }
+
+        let tokens = Self::tokenize(expr)?;
+        if tokens.is_empty() {
+            return Ok(0);
+        }
+
+        let mut arithmetic = Self {
+            expr,
+            tokens,
+            pos: 0,
+            state,
+            depth,
+        };
+
+        let value = arithmetic.comma(true)?;
+        match arithmetic.tokens.get(arithmetic.pos) {
+            Some(_) => Err(arithmetic.error("syntax error in expression")),
+            None => Ok(value),
+        }
+    }
+
+    fn tokenize(expr: &str) -> Result<Vec<(Token, usize)>, String> {
+        let mut tokens = Vec::new();
+        let mut i = 0;
+
+        while i < expr.len() {
+            let rest = &expr[i..];
+            let ch = rest.chars().next().unwrap_or_default();
+
+            if ch.is_whitespace() {
+                i += ch.len_utf8();
+            } else if ch.is_ascii_alphanumeric() || ch == '_' {
+                let len = rest
+                    .find(|c: char| {
+                        !(c.is_ascii_alphanumeric() || c == '_' || c == '#' || c == '@')
+                    })
+                    .unwrap_or(rest.len());
+                let word = &rest[..len];
+
+                let token = if ch.is_ascii_digit() {
+                    Token::Number(Self::number(word).ok_or_else(|| {
+                        format!(
+                            "{}: value too great for base (error token is \"{}\")",
+                            expr.trim(),
+                            word
+                        )
+                    })?)
+                } else {
+                    Token::Name(word.to_string())
+                };
+
+                tokens.push((token, i));
+                i += len;
+            } else if ch == ',' {
+                tokens.push((Token::Comma, i));
+                i += 1;
+            } else {
+                match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                    Some(op) => {
+                        tokens.push((Token::Op(op), i));
+                        i += op.len();
+                    }
+                    None => {
+                        return Err(format!(
+                            "{}: syntax error: invalid arithmetic operator (error token is \"{}\")",
+                            expr.trim(),
+                            rest.trim()
+                        ))
+                    }
+                }
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    /// Reads a literal: decimal, `0x` hexadecimal, `0` octal or `base#digits`.
+    fn number(word: &str) -> Option<i64> {
+        let (base, digits) = match word.split_once('#') {
+            Some((base, digits)) => (base.parse::<u32>().ok()?, digits),
+            None if word.starts_with("0x") || word.starts_with("0X") => (16, &word[2..]),
+            None if word.starts_with('0') && word.len() > 1 => (8, &word[1..]),
+            None => (10, word),
+        };
+
+        if !(2..=64).contains(&base) || digits.is_empty() {
+            return None;
+        }
+
+        digits.chars().try_fold(0i64, |value, ch| {
+            let digit = match ch {
+                '0'..='9' => ch as u32 - '0' as u32,
+                'a'..='z' => ch as u32 - 'a' as u32 + 10,
+                // Upper case letters are the same digits as lower case ones up to base 36.
+                'A'..='Z' if base <= 36 => ch as u32 - 'A' as u32 + 10,
+                'A'..='Z' => ch as u32 - 'A' as u32 + 36,
+                '@' => 62,
+                '_' => 63,
+                _ => return None,
+            };
+
+            (digit < base).then(|| value.wrapping_mul(base as i64).wrapping_add(digit as i64))
+        })
+    }
+
+    fn error(&self, message: &str) -> String {
+        let rest = match self.tokens.get(self.pos) {
+            Some((_, offset)) => self.expr[*offset..].trim(),
+            None => "",
+        };
+
+        format!(
+            "{}: {} (error token is \"{}\")",
+            self.expr.trim(),
+            message,
+            rest
+        )
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("syntax error: `{}' expected", op)))
+        }
+    }
+
+    /// `expr, expr`: evaluates both, the value is the last one.
+    fn comma(&mut self, eval: bool) -> Result<i64, String> {
+        let mut value = self.assignment(eval)?;
+
+        while self.peek() == Some(&Token::Comma) {
+            self.pos += 1;
+            value = self.assignment(eval)?;
+        }
+
+        Ok(value)
+    }
+
+    fn assignment(&mut self, eval: bool) -> Result<i64, String> {
+        let (name, op) = match (self.peek(), self.tokens.get(self.pos + 1)) {
+            (Some(Token::Name(name)), Some((Token::Op(op), _))) if ASSIGNMENTS.contains(op) => {
+                (name.clone(), *op)
+            }
+            _ => return self.conditional(eval),
+        };
+        self.pos += 2;
+
+        let right = self.assignment(eval)?;
+        if !eval {
+            return Ok(0);
+        }
+
+        let value = match op {
+            "=" => right,
+            _ => {
+                let left = self.variable(&name)?;
+                self.apply(&op[..op.len() - 1], left, right)?
+            }
+        };
+
+        self.state.vars_mut().set(&name, &value.to_string());
+        Ok(value)
+    }
+
+    /// `cond ? expr : expr`, only the chosen branch is evaluated.
+    fn conditional(&mut self, eval: bool) -> Result<i64, String> {
+        let condition = self.binary(0, eval)?;
+
+        if self.peek_op() != Some("?") {
+            return Ok(condition);
+        }
+
+        self.pos += 1;
+        let when_true = self.comma(eval && condition != 0)?;
+        self.expect(":")?;
+        let when_false = self.conditional(eval && condition == 0)?;
+
+        Ok(if condition != 0 {
+            when_true
+        } else {
+            when_false
+        })
+    }
+
+    fn binary(&mut self, level: usize, eval: bool) -> Result<i64, String> {
+        if level == LEVELS.len() {
+            return self.power(eval);
+        }
+
+        let mut left = self.binary(level + 1, eval)?;
+
+        while let Some(op) = self.peek_op().filter(|op| LEVELS[level].contains(op)) {
+            self.pos += 1;
+
+            // `&&` and `||` skip the side effects of the right side when the left one
+            // decides the result.
+            let eval_right = match op {
+                "&&" => eval && left != 0,
+                "||" => eval && left == 0,
+                _ => eval,
+            };
+
+            let right = self.binary(level + 1, eval_right)?;
+            left = if eval {
+                self.apply(op, left, right)?
+            } else {
+                0
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn power(&mut self, eval: bool) -> Result<i64, String> {
+        let base = self.unary(eval)?;
+
+        if self.peek_op() != Some("**") {
+            return Ok(base);
+        }
+
+        self.pos += 1;
+        let exponent = self.power(eval)?;
+
+        if eval {
+            self.apply("**", base, exponent)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn unary(&mut self, eval: bool) -> Result<i64, String> {
+        match self.peek_op() {
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                let name = self.name()?;
+
+                if !eval {
+                    return Ok(0);
+                }
+
+                let value = self.variable(&name)?;
+                let value = if op == "++" {
+                    value.wrapping_add(1)
+                } else {
+                    value.wrapping_sub(1)
+                };
+
+                self.state.vars_mut().set(&name, &value.to_string());
+                Ok(value)
+            }
+            Some(op @ ("+" | "-" | "!" | "~")) => {
+                self.pos += 1;
+                let value = self.unary(eval)?;
+
+                Ok(match op {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                })
+            }
+            _ => self.postfix(eval),
+        }
+    }
+
+    fn postfix(&mut self, eval: bool) -> Result<i64, String> {
+        if let (Some(Token::Name(name)), Some((Token::Op(op @ ("++" | "--")), _))) =
+            (self.peek(), self.tokens.get(self.pos + 1))
+        {
+            let (name, op) = (name.clone(), *op);
+            self.pos += 2;
+
+            if !eval {
+                return Ok(0);
+            }
+
+            let value = self.variable(&name)?;
+            let updated = if op == "++" {
+                value.wrapping_add(1)
+            } else {
+                value.wrapping_sub(1)
+            };
+
+            self.state.vars_mut().set(&name, &updated.to_string());
+            return Ok(value);
+        }
+
+        self.primary(eval)
+    }
+
+    fn primary(&mut self, eval: bool) -> Result<i64, String> {
+        match self.peek().cloned() {
+            Some(Token::Number(value)) => {
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                if eval {
+                    self.variable(&name)
+                } else {
+                    Ok(0)
+                }
+            }
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                let value = self.comma(eval)?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            _ => Err(self.error("syntax error: operand expected")),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.peek().cloned() {
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.error("attempted assignment to non-variable")),
+        }
+    }
+
+    /// The value of a variable, whose content is itself evaluated. Unset and empty
+    /// variables are 0.
+    fn variable(&mut self, name: &str) -> Result<i64, String> {
+        let value = match self.state.vars().get(name) {
+            Some(value) if !value.trim().is_empty() => value.to_string(),
+            _ => return Ok(0),
+        };
+
+        Arithmetic::evaluate(&value, self.state, self.depth + 1)
+    }
+
+    fn apply(&self, op: &str, left: i64, right: i64) -> Result<i64, String> {
+        Ok(match op {
+            "||" => (left != 0 || right != 0) as i64,
+            "&&" => (left != 0 && right != 0) as i64,
+            "|" => left | right,
+            "^" => left ^ right,
+            "&" => left & right,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "<" => (left < right) as i64,
+            "<=" => (left <= right) as i64,
+            ">" => (left > right) as i64,
+            ">=" => (left >= right) as i64,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => {
+                return Err(format!("{}: division by 0", self.expr.trim()));
+            }
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "**" if right < 0 => {
+                return Err(format!("{}: exponent less than 0", self.expr.trim()));
+            }
+            "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+            _ => unreachable!(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> Result<i64, String> {
+        evaluate(expr, &mut State::new())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(eval("-2 ** 2"), Ok(4));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("1 | 2 ^ 3 & 6"), Ok(1));
+        assert_eq!(eval("1 < 2 == 1"), Ok(1));
+        assert_eq!(eval("0 || 1 && 0"), Ok(0));
+        assert_eq!(eval("1 ? 2 : 0 ? 3 : 4"), Ok(2));
+        assert_eq!(eval("!0 + ~0"), Ok(0));
+        assert_eq!(eval("1, 2, 3"), Ok(3));
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(eval("9223372036854775807 + 1"), Ok(i64::MIN));
+        assert_eq!(eval("-9223372036854775807 - 2"), Ok(i64::MAX));
+        assert_eq!(eval("2 ** 64"), Ok(0));
+        assert_eq!(eval("(-9223372036854775807 - 1) / -1"), Ok(i64::MIN));
+        assert_eq!(eval("7 % -3"), Ok(1));
+        assert_eq!(eval("-7 / 2"), Ok(-3));
+    }
+
+    #[test]
+    fn bases() {
+        assert_eq!(eval("0x1F"), Ok(31));
+        assert_eq!(eval("010"), Ok(8));
+        assert_eq!(eval("2#1011"), Ok(11));
+        assert_eq!(eval("16#ff"), Ok(255));
+        assert_eq!(eval("36#z"), Ok(35));
+        assert_eq!(eval("64#_"), Ok(63));
+        assert!(eval("08").is_err());
+        assert!(eval("2#2").is_err());
+    }
+
+    #[test]
+    fn variables() {
+        let mut state = State::new();
+        assert_eq!(evaluate("a = 5, a += 2, a * 2", &mut state), Ok(14));
+        assert_eq!(evaluate("b = a++ + ++a", &mut state), Ok(16));
+        assert_eq!(state.vars().get("a"), Some("9"));
+        state.vars_mut().set("c", "a + 1");
+        assert_eq!(evaluate("c * 2", &mut state), Ok(20));
+        assert_eq!(evaluate("unset_in_test + 1", &mut state), Ok(1));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("1 % 0").is_err());
+        assert!(eval("(1 + 2").is_err());
+        assert!(eval("1 +").is_err());
+        assert!(eval("2 ** -1").is_err());
+        assert!(eval("5 = 3").is_err());
+    }
+}
@@ -0,0 +1,974 @@
+use std::{collections::VecDeque, fmt, rc::Rc};
+
+use crate::{
+    lexer::{Lexer, Span, Token, TokenKind},
+    operators::{Redirect, RedirectType},
+    parser::WordParser,
+};
+
+/// A word as typed, expanded when its command runs.
+#[derive(Debug, Clone)]
+pub struct Word {
+    pub text: String,
+    pub span: Span,
+}
+
+/// A command name with its arguments, preceded by the variables assigned for it,
+/// and its redirections.
+#[derive(Debug)]
+pub struct SimpleCommand {
+    pub assignments: Vec<Word>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Simple(SimpleCommand),
+    /// A compound command with the redirections applied to it as a whole.
+    Compound(CompoundCommand, Vec<Redirect>),
+    /// `name() compound` or `function name compound`, kept by the shell when run.
+    Function {
+        name: String,
+        body: Rc<Command>,
+    },
+}
+
+#[derive(Debug)]
+pub enum CompoundCommand {
+    /// `{ list; }`, run in the shell itself.
+    BraceGroup(List),
+    /// `( list )`, run in a copy of the shell so its changes don't outlive it.
+    Subshell(List),
+    /// `if list; then list; [elif list; then list;]... [else list;] fi`
+    If {
+        branches: Vec<(List, List)>,
+        otherwise: Option<List>,
+    },
+    /// `while list; do list; done`, or `until` when the condition is negated.
+    While {
+        condition: List,
+        body: List,
+        until: bool,
+    },
+    /// `for name [in words]; do list; done`
+    For {
+        name: String,
+        words: Option<Vec<Word>>,
+        body: List,
+    },
+    /// `for ((init; condition; step)); do list; done`
+    ArithFor {
+        init: String,
+        condition: String,
+        step: String,
+        body: List,
+    },
+    /// `case word in [(]pattern [| pattern]...) list ;; ... esac`
+    Case { word: Word, items: Vec<CaseItem> },
+}
+
+/// The patterns of a `case` branch with the commands they run.
+#[derive(Debug)]
+pub struct CaseItem {
+    pub patterns: Vec<Word>,
+    pub body: List,
+    pub terminator: Terminator,
+}
+
+/// What follows a `case` branch that matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    /// `;;`, the `case` is done.
+    Break,
+    /// `;&`, the next branch runs too.
+    FallThrough,
+    /// `;;&`, the next branches are tested.
+    Resume,
+}
+
+/// Commands connected by `|`.
+#[derive(Debug)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+    /// Set by a leading `!`, which inverts the status.
+    pub negated: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connector {
+    /// `&&`, runs the next pipeline if the previous one succeeded.
+    And,
+    /// `||`, runs the next pipeline if the previous one failed.
+    Or,
+}
+
+/// Pipelines connected by `&&` and `||`, run from left to right.
+#[derive(Debug)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+}
+
+/// An and-or list ended by `;`, `&` or a newline. After `&` it runs in the
+/// background.
+#[derive(Debug)]
+pub struct Item {
+    pub and_or: AndOr,
+    pub background: bool,
+}
+
+/// Commands run one after the other.
+#[derive(Debug, Default)]
+pub struct List {
+    pub items: Vec<Item>,
+}
+
+/// An input that can't be parsed, with the bytes of the input it points at.
+#[derive(Debug)]
+pub enum SyntaxError {
+    /// A token where the grammar doesn't allow it, `newline` for the end of a line.
+    UnexpectedToken(String, Span),
+    /// The input ended where more was expected, like after `|`.
+    UnexpectedEof(Span),
+    /// A quote, substitution or group missing its closing character.
+    Unclosed(char, Span),
+}
+
+impl SyntaxError {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::UnexpectedToken(_, span)
+            | Self::UnexpectedEof(span)
+            | Self::Unclosed(_, span) => *span,
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedToken(token, _) => {
+                write!(f, "syntax error near unexpected token `{}'", token)
+            }
+            Self::UnexpectedEof(_) => write!(f, "syntax error: unexpected end of file"),
+            Self::Unclosed(close, _) => {
+                write!(f, "unexpected EOF while looking for matching `{}'", close)
+            }
+        }
+    }
+}
+
+/// Reserved words that end a list inside a compound command.
+const CLOSING_WORDS: [&str; 8] = ["then", "elif", "else", "fi", "do", "done", "esac", "}"];
+
+/// A recursive descent parser over the tokens of an input:
+///
+/// ```text
+/// list     := linebreak [and_or (separator linebreak and_or)* [separator]]
+/// and_or   := pipeline (("&&" | "||") linebreak pipeline)*
+/// pipeline := command ("|" linebreak command)*
+/// command  := compound redirect* | function | (assignment | word | redirect)+
+/// compound := "{" list "}" | if | while | until | for | case
+/// function := (name "(" ")" | "function" name ["(" ")"]) linebreak compound redirect*
+/// ```
+///
+/// Reserved words like `if` and `done` are only recognized where a command starts.
+pub struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    here_docs: VecDeque<String>,
+    /// Length of the input, where an error at its end points.
+    end: usize,
+}
+
+impl Parser {
+    fn new(lexer: Lexer, end: usize) -> Self {
+        Self {
+            tokens: lexer.tokens,
+            pos: 0,
+            here_docs: lexer.here_docs.into(),
+            end,
+        }
+    }
+
+    /// Parses a whole input. With `comments`, a `#` starting a word starts a comment
+    /// up to the end of the line.
+    pub fn parse(text: &str, comments: bool) -> Result<List, SyntaxError> {
+        let lexer = Lexer::tokenize(text, comments);
+        if let Some((close, at)) = lexer.unclosed {
+            return Err(SyntaxError::Unclosed(close, Span::new(at, text.len())));
+        }
+
+        Self::new(lexer, text.len()).program()
+    }
+
+    /// Whether the input needs more lines: it ends inside quotes, a substitution, a
+    /// compound command or a here-document, after a backslash, or after `|`, `&&` or
+    /// `||`.
+    pub fn is_incomplete(text: &str, comments: bool) -> bool {
+        let lexer = Lexer::tokenize(text, comments);
+        if !lexer.complete {
+            return true;
+        }
+
+        matches!(
+            Self::new(lexer, text.len()).program(),
+            Err(SyntaxError::UnexpectedEof(_))
+        )
+    }
+
+    fn program(&mut self) -> Result<List, SyntaxError> {
+        let list = self.list()?;
+
+        match self.peek() {
+            Some(_) => Err(self.unexpected()),
+            None => Ok(list),
+        }
+    }
+
+    fn list(&mut self) -> Result<List, SyntaxError> {
+        let mut list = List::default();
+
+        loop {
+            self.skip_newlines();
+            if !self.at_command() {
+                return Ok(list);
+            }
+
+            let and_or = self.and_or()?;
+            let background = self.eat_operator("&");
+            let separated = background || self.eat_operator(";") || self.eat_newline();
+            list.items.push(Item { and_or, background });
+
+            if !separated {
+                return Ok(list);
+            }
+        }
+    }
+
+    fn and_or(&mut self) -> Result<AndOr, SyntaxError> {
+        let first = self.pipeline()?;
+        let mut rest = Vec::new();
+
+        loop {
+            let connector = if self.eat_operator("&&") {
+                Connector::And
+            } else if self.eat_operator("||") {
+                Connector::Or
+            } else {
+                break;
+            };
+
+            self.skip_newlines();
+            rest.push((connector, self.pipeline()?));
+        }
+
+        Ok(AndOr { first, rest })
+    }
+
+    fn pipeline(&mut self) -> Result<Pipeline, SyntaxError> {
+        let negated = self.eat_word("!");
+        // Like in bash, `!` alone negates an empty pipeline, which succeeds.
+        if negated && !self.at_command() {
+            return Ok(Pipeline {
+                commands: Vec::new(),
+                negated,
+            });
+        }
+
+        let mut commands = vec![self.command()?];
+
+        while self.eat_operator("|") {
+            self.skip_newlines();
+            commands.push(self.command()?);
+        }
+
+        Ok(Pipeline { commands, negated })
+    }
+
+    fn command(&mut self) -> Result<Command, SyntaxError> {
+        if let Some(command) = self.compound_command()? {
+            return Ok(command);
+        }
+
+        let is_function = self.at_word("function")
+            || self
+                .tokens
+                .get(self.pos + 1)
+                .is_some_and(|token| token.is_operator("("));
+
+        match self.peek().map(|token| &token.kind) {
+            Some(TokenKind::Word(_)) if is_function => self.function_definition(),
+            Some(TokenKind::Word(word)) if CLOSING_WORDS.contains(&word.as_str()) => {
+                Err(self.unexpected())
+            }
+            _ => Ok(Command::Simple(self.simple_command()?)),
+        }
+    }
+
+    /// A compound command with its redirections, or `None` if the next token doesn't
+    /// start one.
+    fn compound_command(&mut self) -> Result<Option<Command>, SyntaxError> {
+        let compound = match self.peek().map(|token| &token.kind) {
+            Some(TokenKind::Word(word)) => match word.as_str() {
+                "{" => self.brace_group()?,
+                "if" => self.if_clause()?,
+                "while" | "until" => self.while_clause()?,
+                "for" => self.for_clause()?,
+                "case" => self.case_clause()?,
+                _ => return Ok(None),
+            },
+            Some(TokenKind::Operator(op)) if op == "(" => self.subshell()?,
+            _ => return Ok(None),
+        };
+
+        let mut redirects = Vec::new();
+        while self.peek().is_some_and(Self::is_redirect) {
+            redirects.push(self.redirect()?.0);
+        }
+
+        Ok(Some(Command::Compound(compound, redirects)))
+    }
+
+    fn function_definition(&mut self) -> Result<Command, SyntaxError> {
+        let keyword = self.eat_word("function");
+        let name = self.word()?;
+
+        if self.eat_operator("(") {
+            if !self.eat_operator(")") {
+                return Err(self.unexpected());
+            }
+        } else if !keyword {
+            return Err(self.unexpected());
+        }
+        self.skip_newlines();
+
+        match self.compound_command()? {
+            Some(body) => Ok(Command::Function {
+                name: name.text,
+                body: Rc::new(body),
+            }),
+            None => Err(self.unexpected()),
+        }
+    }
+
+    fn brace_group(&mut self) -> Result<CompoundCommand, SyntaxError> {
+        self.expect_word("{")?;
+        let list = self.compound_list()?;
+        self.expect_word("}")?;
+        Ok(CompoundCommand::BraceGroup(list))
+    }
+
+    fn subshell(&mut self) -> Result<CompoundCommand, SyntaxError> {
+        self.eat_operator("(");
+        let list = self.compound_list()?;
+        if !self.eat_operator(")") {
+            return Err(self.unexpected());
+        }
+        Ok(CompoundCommand::Subshell(list))
+    }
+
+    fn if_clause(&mut self) -> Result<CompoundCommand, SyntaxError> {
+        self.expect_word("if")?;
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+
+        loop {
+            let condition = self.compound_list()?;
+            self.expect_word("then")?;
+            branches.push((condition, self.compound_list()?));
+
+            if self.eat_word("elif") {
+                continue;
+            }
+            if self.eat_word("else") {
+                otherwise = Some(self.compound_list()?);
+            }
+            self.expect_word("fi")?;
+
+            return Ok(CompoundCommand::If {
+                branches,
+                otherwise,
+            });
+        }
+    }
+
+    fn while_clause(&mut self) -> Result<CompoundCommand, SyntaxError> {
+        let until = self.eat_word("until");
+        if !until {
+            self.expect_word("while")?;
+        }
+
+        let condition = self.compound_list()?;
+        let body = self.do_group()?;
+
+        Ok(CompoundCommand::While {
+            condition,
+            body,
+            until,
+        })
+    }
+
+    /// `for name [in words]` or `for ((...))`, then an optional separator and the
+    /// body.
+    fn for_clause(&mut self) -> Result<CompoundCommand, SyntaxError> {
+        self.expect_word("for")?;
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return Err(self.unexpected()),
+        };
+        let name = match &token.kind {
+            TokenKind::Word(word) => word.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+
+        if let Some(expr) = WordParser::arithmetic_command(&name) {
+            let parts: Vec<&str> = expr.split(';').collect();
+            let &[init, condition, step] = parts.as_slice() else {
+                return Err(SyntaxError::UnexpectedToken(name, token.span));
+            };
+            let (init, condition, step) = (init.trim(), condition.trim(), step.trim());
+
+            self.eat_operator(";");
+            self.skip_newlines();
+            return Ok(CompoundCommand::ArithFor {
+                init: init.to_string(),
+                condition: condition.to_string(),
+                step: step.to_string(),
+                body: self.do_group()?,
+            });
+        }
+
+        self.skip_newlines();
+        let mut words = None;
+        if self.eat_word("in") {
+            let mut list = Vec::new();
+            while let Some(Token {
+                kind: TokenKind::Word(text),
+                span,
+            }) = self.peek()
+            {
+                list.push(Word {
+                    text: text.clone(),
+                    span: *span,
+                });
+                self.pos += 1;
+            }
+            words = Some(list);
+
+            if !self.eat_operator(";") && !self.eat_newline() {
+                return Err(self.unexpected());
+            }
+        } else {
+            self.eat_operator(";");
+        }
+        self.skip_newlines();
+
+        Ok(CompoundCommand::For {
+            name,
+            words,
+            body: self.do_group()?,
+        })
+    }
+
+    fn case_clause(&mut self) -> Result<CompoundCommand, SyntaxError> {
+        self.expect_word("case")?;
+        let word = self.word()?;
+        self.skip_newlines();
+        self.expect_word("in")?;
+        self.skip_newlines();
+
+        let mut items = Vec::new();
+        while !self.eat_word("esac") {
+            self.eat_operator("(");
+            let mut patterns = vec![self.word()?];
+            while self.eat_operator("|") {
+                patterns.push(self.word()?);
+            }
+            if !self.eat_operator(")") {
+                return Err(self.unexpected());
+            }
+
+            let body = self.list()?;
+            let terminator = if self.eat_operator(";&") {
+                Terminator::FallThrough
+            } else if self.eat_operator(";;&") {
+                Terminator::Resume
+            } else if self.eat_operator(";;") || self.at_word("esac") {
+                Terminator::Break
+            } else {
+                return Err(self.unexpected());
+            };
+            self.skip_newlines();
+
+            items.push(CaseItem {
+                patterns,
+                body,
+                terminator,
+            });
+        }
+
+        Ok(CompoundCommand::Case { word, items })
+    }
+
+    fn do_group(&mut self) -> Result<List, SyntaxError> {
+        self.expect_word("do")?;
+        let body = self.compound_list()?;
+        self.expect_word("done")?;
+        Ok(body)
+    }
+
+    /// A list inside a compound command, which can't be empty.
+    fn compound_list(&mut self) -> Result<List, SyntaxError> {
+        let list = self.list()?;
+        if list.items.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(list)
+    }
+
+    /// A plain word, like the subject or a pattern of `case`.
+    fn word(&mut self) -> Result<Word, SyntaxError> {
+        match self.peek() {
+            Some(Token {
+                kind: TokenKind::Word(text),
+                span,
+            }) => {
+                let word = Word {
+                    text: text.clone(),
+                    span: *span,
+                };
+                self.pos += 1;
+                Ok(word)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    /// Words up to the next operator. Leading `NAME=value` words are assignments,
+    /// redirections may come anywhere.
+    fn simple_command(&mut self) -> Result<SimpleCommand, SyntaxError> {
+        let mut command = SimpleCommand {
+            assignments: Vec::new(),
+            words: Vec::new(),
+            redirects: Vec::new(),
+            span: self
+                .peek()
+                .map_or(Span::new(self.end, self.end), |t| t.span),
+        };
+
+        loop {
+            let token = match self.peek() {
+                Some(token) if Self::is_redirect(token) => {
+                    let span = token.span;
+                    let (redirect, end) = self.redirect()?;
+                    command.redirects.push(redirect);
+                    command.span = command.span.to(span.to(end));
+                    continue;
+                }
+                Some(token) => token,
+                None => break,
+            };
+
+            let text = match &token.kind {
+                TokenKind::Word(text) => text.clone(),
+                _ => break,
+            };
+            let word = Word {
+                text,
+                span: token.span,
+            };
+            self.pos += 1;
+
+            command.span = command.span.to(word.span);
+            if command.words.is_empty() && WordParser::assignment(&word.text).is_some() {
+                command.assignments.push(word);
+            } else {
+                command.words.push(word);
+            }
+        }
+
+        if command.assignments.is_empty()
+            && command.words.is_empty()
+            && command.redirects.is_empty()
+        {
+            return Err(self.unexpected());
+        }
+
+        Ok(command)
+    }
+
+    /// A redirection operator and its target word, with the span of the target.
+    fn redirect(&mut self) -> Result<(Redirect, Span), SyntaxError> {
+        let op = self.tokens[self.pos].clone();
+        self.pos += 1;
+
+        let target = match self.peek() {
+            Some(Token {
+                kind: TokenKind::Word(word),
+                span,
+            }) => Word {
+                text: word.clone(),
+                span: *span,
+            },
+            Some(_) => return Err(self.unexpected()),
+            // The target is missing at the end of the line.
+            None => {
+                return Err(SyntaxError::UnexpectedToken(
+                    String::from("newline"),
+                    Span::new(self.end, self.end),
+                ))
+            }
+        };
+        self.pos += 1;
+
+        let redirect = match RedirectType::from_str(op.text()) {
+            Ok(RedirectType::HereDoc(fd)) => {
+                let body = self.here_docs.pop_front().unwrap_or_default();
+                Redirect::here_doc(fd, &target.text, &body)
+            }
+            Ok(r_type) => Redirect::new(r_type, &target.text),
+            Err(_) => return Err(SyntaxError::UnexpectedToken(op.text().to_string(), op.span)),
+        };
+
+        Ok((redirect, target.span))
+    }
+
+    fn is_redirect(token: &Token) -> bool {
+        match &token.kind {
+            TokenKind::Operator(op) => RedirectType::from_str(op).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Whether the next token can start a command.
+    fn at_command(&self) -> bool {
+        self.peek().is_some_and(|token| match &token.kind {
+            TokenKind::Word(word) => !CLOSING_WORDS.contains(&word.as_str()),
+            _ => token.is_operator("(") || Self::is_redirect(token),
+        })
+    }
+
+    fn at_word(&self, word: &str) -> bool {
+        self.peek()
+            .is_some_and(|token| matches!(&token.kind, TokenKind::Word(text) if text == word))
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        let found = self.at_word(word);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), SyntaxError> {
+        if self.eat_word(word) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    /// The error for the next token, or for the end of the input.
+    fn unexpected(&self) -> SyntaxError {
+        match self.peek() {
+            Some(token) => SyntaxError::UnexpectedToken(token.text().to_string(), token.span),
+            None => SyntaxError::UnexpectedEof(Span::new(self.end, self.end)),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat_operator(&mut self, op: &str) -> bool {
+        let found = self.peek().is_some_and(|token| token.is_operator(op));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn eat_newline(&mut self) -> bool {
+        let found = self
+            .peek()
+            .is_some_and(|token| token.kind == TokenKind::Newline);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.eat_newline() {}
+    }
+}
+
+impl fmt::Display for SimpleCommand {
+    /// The command as typed, used to name jobs.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts: Vec<String> = self
+            .assignments
+            .iter()
+            .chain(self.words.iter())
+            .map(|word| word.text.clone())
+            .collect();
+        parts.extend(self.redirects.iter().map(Redirect::to_string));
+
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Simple(command) => write!(f, "{}", command),
+            Self::Compound(command, redirects) => {
+                write!(f, "{}", command)?;
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+            Self::Function { name, body } => write!(f, "{}() {}", name, body),
+        }
+    }
+}
+
+impl fmt::Display for CompoundCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BraceGroup(list) => write!(f, "{{ {} }}", list),
+            Self::Subshell(list) => write!(f, "( {} )", list),
+            Self::If {
+                branches,
+                otherwise,
+            } => {
+                for (i, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { " elif" };
+                    write!(f, "{} {} then {}", keyword, condition, body)?;
+                }
+                if let Some(otherwise) = otherwise {
+                    write!(f, " else {}", otherwise)?;
+                }
+                write!(f, " fi")
+            }
+            Self::While {
+                condition,
+                body,
+                until,
+            } => {
+                let keyword = if *until { "until" } else { "while" };
+                write!(f, "{} {} do {} done", keyword, condition, body)
+            }
+            Self::For { name, words, body } => {
+                write!(f, "for {}", name)?;
+                if let Some(words) = words {
+                    write!(f, " in")?;
+                    for word in words {
+                        write!(f, " {}", word.text)?;
+                    }
+                }
+                write!(f, "; do {} done", body)
+            }
+            Self::ArithFor {
+                init,
+                condition,
+                step,
+                body,
+            } => write!(
+                f,
+                "for (({}; {}; {})); do {} done",
+                init, condition, step, body
+            ),
+            Self::Case { word, items } => {
+                write!(f, "case {} in", word.text)?;
+                for item in items {
+                    let patterns: Vec<&str> = item
+                        .patterns
+                        .iter()
+                        .map(|word| word.text.as_str())
+                        .collect();
+                    let terminator = match item.terminator {
+                        Terminator::Break => ";;",
+                        Terminator::FallThrough => ";&",
+                        Terminator::Resume => ";;&",
+                    };
+                    write!(f, " {}) {} {}", patterns.join("|"), item.body, terminator)?;
+                }
+                write!(f, " esac")
+            }
+        }
+    }
+}
+
+impl fmt::Display for List {
+    /// The commands on one line, each ended by `;` or `&`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items: Vec<String> = self
+            .items
+            .iter()
+            .map(|item| {
+                let separator = if item.background { " &" } else { ";" };
+                format!("{}{}", item.and_or, separator)
+            })
+            .collect();
+        write!(f, "{}", items.join(" "))
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let commands: Vec<String> = self.commands.iter().map(Command::to_string).collect();
+        if self.negated {
+            write!(f, "! ")?;
+        }
+        write!(f, "{}", commands.join(" | "))
+    }
+}
+
+impl fmt::Display for AndOr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+        for (connector, pipeline) in &self.rest {
+            match connector {
+                Connector::And => write!(f, " && {}", pipeline)?,
+                Connector::Or => write!(f, " || {}", pipeline)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> String {
+        Parser::parse(text, true).unwrap().to_string()
+    }
+
+    /// The error for `text` with the part of the input its span covers.
+    fn error(text: &str) -> (String, &str) {
+        let err = Parser::parse(text, true).unwrap_err();
+        let span = err.span();
+        (err.to_string(), &text[span.start..span.end])
+    }
+
+    #[test]
+    fn lists_and_pipelines() {
+        assert_eq!(parse("a | b && c || d; e &"), "a | b && c || d; e &");
+        assert_eq!(parse("a\n\nb\n"), "a; b;");
+        assert_eq!(parse("! a | b"), "! a | b;");
+        assert_eq!(
+            parse("x=1 y=2 cmd arg >out 2>&1"),
+            parse("x=1 y=2 cmd arg > out 2>& 1")
+        );
+        assert_eq!(parse(""), "");
+    }
+
+    #[test]
+    fn compound_commands() {
+        assert_eq!(
+            parse("if a; then b; elif c\nthen d; else e; fi"),
+            parse("if a; then b; elif c; then d; else e; fi")
+        );
+        assert_eq!(
+            parse("while a; do b; done | c"),
+            parse("while a\ndo\n  b\ndone | c")
+        );
+        assert_eq!(
+            parse("for i in 1 2; do a; done"),
+            parse("for i in 1 2\ndo a\ndone")
+        );
+        assert_eq!(
+            parse("case x in a|b) c;; (d) e;& *) f;;& esac"),
+            parse("case x in\na|b) c ;;\n(d) e ;&\n*) f ;;&\nesac")
+        );
+        assert_eq!(parse("{ a; b; } > f"), parse("{\na\nb\n} >f"));
+        assert_eq!(parse("(a; b) | c"), parse("( a\nb ) | c"));
+        assert_eq!(parse("f() { a; }"), parse("function f { a; }"));
+    }
+
+    #[test]
+    fn here_documents() {
+        let text = "cat <<A; cat <<-B\nfirst\nA\n\tsecond\n\tB\n";
+        let list = format!("{:?}", Parser::parse(text, true).unwrap());
+        assert!(list.contains("\"first\\n\""));
+        assert!(list.contains("\"second\\n\""));
+        assert!(Parser::is_incomplete("cat <<A\nbody", true));
+        assert!(!Parser::is_incomplete("cat <<A\nbody\nA", true));
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(
+            error("echo a; ; b"),
+            ("syntax error near unexpected token `;'".to_string(), ";")
+        );
+        assert_eq!(
+            error("| a"),
+            ("syntax error near unexpected token `|'".to_string(), "|")
+        );
+        assert_eq!(
+            error("echo > ;"),
+            ("syntax error near unexpected token `;'".to_string(), ";")
+        );
+        assert_eq!(
+            error("echo >"),
+            (
+                "syntax error near unexpected token `newline'".to_string(),
+                ""
+            )
+        );
+        assert_eq!(
+            error("if a; then b; done"),
+            (
+                "syntax error near unexpected token `done'".to_string(),
+                "done"
+            )
+        );
+        assert_eq!(
+            error("echo 'a b"),
+            (
+                "unexpected EOF while looking for matching `''".to_string(),
+                "'a b"
+            )
+        );
+        assert_eq!(
+            error("a && (b"),
+            ("syntax error: unexpected end of file".to_string(), "")
+        );
+        assert_eq!(
+            Parser::parse("a && (b", true).unwrap_err().span(),
+            Span::new(7, 7)
+        );
+        assert_eq!(
+            Parser::parse("x\n  )", true).unwrap_err().span(),
+            Span::new(4, 5)
+        );
+    }
+
+    #[test]
+    fn incomplete_input() {
+        for text in [
+            "a |",
+            "a &&",
+            "if a; then",
+            "for i in 1",
+            "case x in",
+            "f() {",
+            "(a",
+            "echo \"a",
+        ] {
+            assert!(Parser::is_incomplete(text, true), "{}", text);
+        }
+        for text in ["a | b", "if a; then b; fi", "a; ;", "echo )"] {
+            assert!(!Parser::is_incomplete(text, true), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+/// First of the characters standing for the bytes 0x80 to 0xFF that aren't part of
+/// valid UTF-8. They are private use characters at the very end of Unicode, which
+/// text typed at the shell doesn't contain.
+const RAW_BASE: u32 = 0x10FF00;
+
+/// Turns bytes into a string without losing any: valid UTF-8 is kept, every other
+/// byte becomes a character that [`decode`] gives back as that byte.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut text = String::new();
+
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            text.extend(char::from_u32(RAW_BASE + *byte as u32));
+        }
+    }
+
+    text
+}
+
+/// The bytes a string made by [`encode`] stands for.
+pub fn decode(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+
+    for ch in text.chars() {
+        match ch as u32 {
+            raw if raw >= RAW_BASE + 0x80 => bytes.push((raw - RAW_BASE) as u8),
+            _ => bytes.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+
+    bytes
+}
+
+/// [`decode`] for the arguments and environment of a program.
+pub fn to_os_string(text: &str) -> OsString {
+    OsString::from_vec(decode(text))
+}
@@ -1,103 +1,963 @@
-use std::{env, fs, os::unix::fs::PermissionsExt, path::Path, process, sync::OnceLock};
+use std::{
+    env, fs, io,
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+    sync::OnceLock,
+};
 
-use crate::state::State;
+use crate::{
+    arith,
+    ast::{self, SimpleCommand},
+    bytes,
+    fds::Fds,
+    jobs::{self, Jobs, SIGNALS},
+    operators::Operators,
+    parser::WordParser,
+    state::{Jump, State},
+    vars::{Variable, Variables},
+};
 
 pub static CACHE: OnceLock<Vec<fs::DirEntry>> = OnceLock::new();
-pub static COMMANDS: [&str; 5] = ["exit", "echo", "type", "pwd", "cd"];
+pub static COMMANDS: [&str; 22] = [
+    "exit", "echo", "type", "pwd", "cd", "jobs", "fg", "bg", "wait", "kill", "disown", "export",
+    "unset", "set", "env", "let", "shopt", "break", "continue", "local", "return", "shift",
+];
 
+/// A `NAME=value` word in front of a command, with its value expanded.
+type Assignment = (String, String);
+
+/// A simple command of the syntax tree, with the result of its last run.
 #[derive(Debug)]
-pub struct Command {
-    cmd: CommandType,
-    output: Option<String>,
-    error: Option<String>,
+pub struct Command<'a> {
+    command: &'a SimpleCommand,
+    output: Option<Vec<u8>>,
+    error: Option<Vec<u8>>,
+    status: i32,
 }
 
-impl Command {
-    pub fn new(cmd: CommandType) -> Self {
+impl<'a> Command<'a> {
+    pub fn new(command: &'a SimpleCommand) -> Self {
         Self {
-            cmd,
+            command,
             output: None,
             error: None,
+            status: 0,
+        }
+    }
+
+    pub fn status(&self) -> i32 {
+        self.status
+    }
+
+    /// Expands the words at execution time, so each command of a list sees the
+    /// effects of the commands that ran before it.
+    fn expand(&self, state: &mut State) -> Result<(Vec<Assignment>, Vec<String>), String> {
+        let assignments = self.expand_assignments(state)?;
+
+        let mut fields = Vec::new();
+        for word in &self.command.words {
+            let word = word.text.as_str();
+            match WordParser::arithmetic_command(word) {
+                // The expression is expanded like inside double quotes.
+                Some(expr) if fields.is_empty() => {
+                    fields.push(format!("(({}))", WordParser::expand(expr, state)?));
+                }
+                _ => fields.extend(WordParser::expand_words(word, state)?),
+            }
         }
+
+        Ok((assignments, fields))
     }
 
-    pub fn exec(&mut self, state: &mut State) {
-        self.exec_cmd(state);
+    /// Expands the assignments from left to right, each seeing the values assigned
+    /// before it, as in `x=1 y=$x`. The variables get their old values back here, the
+    /// caller decides how long the new ones last.
+    fn expand_assignments(&self, state: &mut State) -> Result<Vec<Assignment>, String> {
+        let mut assignments = Vec::new();
+        let mut saved = Vec::new();
+        let mut result = Ok(());
+
+        for word in &self.command.assignments {
+            let Some((name, value)) = WordParser::assignment(&word.text) else {
+                continue;
+            };
+
+            match WordParser::expand_assignment(value, state) {
+                Ok(value) => {
+                    saved.push((name, state.vars().get_variable(name)));
+                    state.vars_mut().set(name, &value);
+                    assignments.push((name.to_string(), value));
+                }
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        for (name, var) in saved.into_iter().rev() {
+            state.vars_mut().restore(name, var);
+        }
+
+        result.map(|_| assignments)
     }
 
-    pub fn output(&self) -> Option<&String> {
-        match &self.output {
-            Some(o) => Some(o),
-            None => None,
+    /// Starts the command with the given file descriptors, after expanding its words
+    /// and applying its redirections to them from left to right. External programs are spawned and
+    /// returned so they can run concurrently with other pipeline stages, builtins run
+    /// to completion and leave their result in `output`/`error`. With job control the
+    /// process joins the group `pgid`, or leads a new one if it is 0.
+    pub fn spawn(&mut self, state: &mut State, fds: &mut Fds, pgid: i32) -> Option<process::Child> {
+        self.start(state, fds, pgid, false)
+    }
+
+    /// Runs the command in a forked copy of the shell, like a pipeline stage. A
+    /// program replaces the process, a builtin runs and writes its output, and the
+    /// status is returned for the copy to exit with.
+    pub fn exec(&mut self, state: &mut State, fds: &mut Fds) -> i32 {
+        self.start(state, fds, 0, true);
+        self.flush(state, fds);
+        self.status
+    }
+
+    /// Spawns a program, or runs it in place of the shell if `replace` is set.
+    fn start(
+        &mut self,
+        state: &mut State,
+        fds: &mut Fds,
+        pgid: i32,
+        replace: bool,
+    ) -> Option<process::Child> {
+        self.status = 0;
+        state.take_substitution_status();
+
+        let (assignments, words) = match self.expand(state) {
+            Ok(expanded) => expanded,
+            Err(err) => {
+                self.fail(1, &err);
+                return None;
+            }
+        };
+
+        // The words are expanded first, so `echo $(cat f) > f` reads the file before
+        // the redirection truncates it.
+        for redirect in self.command.redirects.iter() {
+            if let Err(err) = redirect.apply(fds, state) {
+                self.status = 1;
+                self.write_error(&format!("{}\n", err));
+                return None;
+            }
+        }
+
+        // Without a command the assignments set shell variables, and the command gets
+        // the status of the last substitution in them.
+        let substitution_status = state.take_substitution_status();
+        if words.is_empty() {
+            for (name, value) in assignments {
+                state.vars_mut().set(&name, &value);
+            }
+            self.status = substitution_status.unwrap_or(0);
+            return None;
+        }
+
+        // Otherwise they are exported for this command only, which also makes an
+        // assigned `PATH` apply to the lookup of the command.
+        let saved: Vec<(String, Option<Variable>)> = assignments
+            .iter()
+            .map(|(name, _)| (name.clone(), state.vars().get_variable(name)))
+            .collect();
+
+        for (name, value) in assignments.iter() {
+            state.vars_mut().set(name, value);
+            state.vars_mut().export(name, true);
+        }
+
+        let cmd = CommandType::parse(words, state);
+        let child = match cmd {
+            CommandType::External {
+                command,
+                path,
+                args,
+                env,
+            } => {
+                let mut child = process::Command::new(path);
+                child
+                    .arg0(&command)
+                    .args(args.iter().map(|arg| bytes::to_os_string(arg)))
+                    .env_clear()
+                    .envs(env.iter().map(|(name, value)| {
+                        (bytes::to_os_string(name), bytes::to_os_string(value))
+                    }));
+
+                if state.jobs().job_control() {
+                    child.process_group(pgid);
+                }
+
+                // SAFETY: only resets signal dispositions, which is async-signal-safe.
+                unsafe {
+                    child.pre_exec(|| {
+                        Jobs::reset_signals();
+                        Ok(())
+                    });
+                }
+
+                let started = fds.apply(&mut child).and_then(|_| match replace {
+                    // Only returns if the program couldn't be run.
+                    true => Err(child.exec()),
+                    false => child.spawn(),
+                });
+
+                match started {
+                    Ok(child) => Some(child),
+                    Err(err) => {
+                        self.status = match err.kind() {
+                            io::ErrorKind::NotFound => 127,
+                            _ => 126,
+                        };
+                        self.write_error(&format!("{}: {}\n", command, io_error_message(&err)));
+                        None
+                    }
+                }
+            }
+            // The body runs in the shell with the redirected descriptors in place of
+            // its own.
+            CommandType::Function(body, args) => {
+                match fds.install() {
+                    Ok(saved) => {
+                        Operators::call_function(&body, args, state);
+                        Fds::restore(saved);
+                        self.status = state.status();
+                    }
+                    Err(err) => self.fail(1, &io_error_message(&err)),
+                }
+                None
+            }
+            cmd => {
+                self.exec_cmd(&cmd, state);
+                None
+            }
+        };
+
+        for (name, var) in saved.into_iter().rev() {
+            state.vars_mut().restore(&name, var);
         }
+
+        child
     }
 
-    pub fn error(&self) -> Option<&String> {
-        match &self.error {
-            Some(e) => Some(e),
-            None => None,
+    /// Writes what the command left in `output`/`error` to its descriptors.
+    pub fn flush(&mut self, state: &mut State, fds: &Fds) {
+        if let Some(output) = self.output.take() {
+            fds.write(1, &output, state);
+        }
+
+        if let Some(error) = self.error.take() {
+            fds.write(2, &error, state);
         }
     }
 
     fn write_output(&mut self, text: &str) {
-        self.output = Some(text.to_string());
+        self.output
+            .get_or_insert_with(Vec::new)
+            .extend_from_slice(&bytes::decode(text));
     }
 
     fn write_error(&mut self, text: &str) {
-        self.error = Some(text.to_string());
+        self.error
+            .get_or_insert_with(Vec::new)
+            .extend_from_slice(text.as_bytes());
+    }
+
+    fn fail(&mut self, status: i32, text: &str) {
+        self.status = status;
+        self.write_error(&format!("{}\n", text));
     }
 
-    fn exec_cmd(&mut self, state: &mut State) {
-        match &self.cmd {
+    fn exec_cmd(&mut self, cmd: &CommandType, state: &mut State) {
+        self.status = 0;
+
+        match cmd {
             CommandType::Unknown(cmd) => {
+                self.status = 127;
                 self.write_error(&format!("{}: command not found\n", cmd.trim_end()))
             }
-            CommandType::Exit(code) => process::exit(*code),
+            CommandType::Exit(args) => self.exit(args, state),
             CommandType::Echo(text) => self.write_output(&format!("{}\n", text)),
             CommandType::Type(cmd) => {
-                if COMMANDS.contains(&cmd.trim_start()) {
+                if let Some(body) = state.function(cmd) {
+                    self.write_output(&format!("{} is a function\n{} () {}\n", cmd, cmd, body));
+                } else if COMMANDS.contains(&cmd.trim_start()) {
                     self.write_output(&format!("{} is a shell builtin\n", cmd));
-                } else if let Some(entry) = CommandType::find_ext_command(cmd) {
-                    self.write_output(&format!("{} is {}\n", cmd, entry.path().to_str().unwrap()));
+                } else if let Some(path) =
+                    CommandType::find_ext_command(cmd, state.vars().get("PATH"))
+                {
+                    self.write_output(&format!("{} is {}\n", cmd, path.display()));
                 } else {
+                    self.status = 1;
                     self.write_error(&format!("{}: not found\n", cmd));
                 }
             }
-            CommandType::External { command, args } => {
-                let output = process::Command::new(command)
-                    .args(args)
-                    .output()
-                    .expect("Failed to execute command");
+            CommandType::External { .. } | CommandType::Function(..) => unreachable!(),
+            CommandType::Pwd(path) => self.write_output(&format!("{}\n", path)),
+            CommandType::Cd(args) => self.cd(args, state),
+            CommandType::Export(args) => self.export(args, state),
+            CommandType::Unset(args) => self.unset(args, state),
+            CommandType::Set(args) => match args.first() {
+                None => {
+                    let list = state.vars().list();
+                    self.write_output(&list);
+                }
+                Some(arg) if arg == "--" => state.set_args(args[1..].to_vec()),
+                Some(arg) => self.fail(2, &format!("set: {}: invalid option", arg)),
+            },
+            CommandType::Shopt(args) => self.shopt(args, state),
+            CommandType::Env(env) => {
+                let list: String = env
+                    .iter()
+                    .map(|(name, value)| format!("{}={}\n", name, value))
+                    .collect();
+                self.write_output(&list);
+            }
+            CommandType::Failed(status, message) => self.fail(*status, message),
+            CommandType::Let(exprs) => {
+                if exprs.is_empty() {
+                    return self.fail(1, "let: expression expected");
+                }
 
-                let stdout = String::from_utf8(output.stdout).expect("Failed to read stdout");
-                let stderr = String::from_utf8(output.stderr).expect("Failed to read stderr");
+                for expr in exprs {
+                    match arith::evaluate(expr, state) {
+                        Ok(value) => self.status = (value == 0) as i32,
+                        Err(err) => return self.fail(1, &format!("let: {}", err)),
+                    }
+                }
+            }
+            CommandType::Break(args) => self.loop_jump("break", args, state, Jump::Break),
+            CommandType::Continue(args) => self.loop_jump("continue", args, state, Jump::Continue),
+            CommandType::Local(args) => self.local(args, state),
+            CommandType::Return(args) => self.leave_function(args, state),
+            CommandType::Shift(args) => self.shift(args, state),
+            CommandType::Arithmetic(expr) => match arith::evaluate(expr, state) {
+                Ok(value) => self.status = (value == 0) as i32,
+                Err(err) => self.fail(1, &err),
+            },
+            CommandType::Jobs => {
+                let list = state.jobs_mut().list();
+                self.write_output(&list);
+            }
+            CommandType::Fg(spec) => self.foreground(spec.as_deref(), state),
+            CommandType::Bg(spec) => {
+                if !state.jobs().job_control() {
+                    return self.fail(1, "bg: no job control");
+                }
 
-                self.write_output(&stdout.to_string());
-                self.write_error(&stderr.to_string());
+                match state.jobs().find(spec.as_deref()) {
+                    Ok(index) => {
+                        let report = state.jobs_mut().background(index);
+                        self.write_output(&report);
+                    }
+                    Err(err) => self.fail(1, &format!("bg: {}", err)),
+                }
             }
-            CommandType::Pwd(path) => self.write_output(&format!("{}\n", path)),
-            CommandType::Cd(path) => match fs::exists(path) {
-                Ok(true) => {
-                    state.set_pwd(path);
+            CommandType::Wait(args) => self.wait(args, state),
+            CommandType::Kill(args) => self.kill(args, state),
+            CommandType::Disown(args) => {
+                if args.iter().any(|arg| arg == "-a") {
+                    while !state.jobs().is_empty() {
+                        state.jobs_mut().remove(0);
+                    }
+                    return;
                 }
-                Ok(false) => {
-                    self.write_error(&format!("cd: {}: No such file or directory\n", path));
+
+                let specs: Vec<Option<&str>> = if args.is_empty() {
+                    vec![None]
+                } else {
+                    args.iter().map(|arg| Some(arg.as_str())).collect()
+                };
+
+                for spec in specs {
+                    match state.jobs().find(spec) {
+                        Ok(index) => {
+                            state.jobs_mut().remove(index);
+                        }
+                        Err(err) => self.fail(1, &format!("disown: {}", err)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// `unset [-f|-v] name...`: removes variables with `-v`, functions with `-f`, and
+    /// without either the variable or else the function of that name.
+    fn unset(&mut self, args: &[String], state: &mut State) {
+        let (mut functions, mut variables) = (false, false);
+        let mut args = args.iter().peekable();
+
+        while let Some(arg) = args.next_if(|arg| arg.len() > 1 && arg.starts_with('-')) {
+            match arg.as_str() {
+                "--" => break,
+                "-f" => functions = true,
+                "-v" => variables = true,
+                _ => {
+                    return self.fail(
+                        2,
+                        &format!(
+                            "unset: {}: invalid option\nunset: usage: unset [-f] [-v] [name ...]",
+                            arg
+                        ),
+                    )
+                }
+            }
+        }
+
+        for name in args {
+            if functions {
+                state.unset_function(name);
+            } else if !Variables::is_valid_name(name) {
+                self.fail(1, &format!("unset: `{}': not a valid identifier", name));
+            } else if variables || state.vars().get_variable(name).is_some() {
+                state.vars_mut().unset(name);
+            } else {
+                state.unset_function(name);
+            }
+        }
+    }
+
+    /// `export [-n] [-p] [name[=value]...]`: marks variables for the environment of
+    /// programs, `-n` removes the mark. Without names the exported variables are
+    /// listed.
+    fn export(&mut self, args: &[String], state: &mut State) {
+        let mut exported = true;
+        let mut names = Vec::new();
+
+        for arg in args {
+            match arg.as_str() {
+                "-n" => exported = false,
+                "-p" => (),
+                _ => names.push(arg),
+            }
+        }
+
+        if names.is_empty() {
+            let list = state.vars().exports();
+            return self.write_output(&list);
+        }
+
+        for arg in names {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+
+            if !Variables::is_valid_name(name) {
+                self.fail(1, &format!("export: `{}': not a valid identifier", arg));
+                continue;
+            }
+
+            if let Some(value) = value {
+                state.vars_mut().set(name, value);
+            }
+            state.vars_mut().export(name, exported);
+        }
+    }
+
+    /// `break [n]` and `continue [n]`: leaves or restarts the `n`th enclosing loop,
+    /// the outermost one if there are fewer.
+    fn loop_jump(
+        &mut self,
+        name: &str,
+        args: &[String],
+        state: &mut State,
+        jump: fn(usize) -> Jump,
+    ) {
+        let count = match args.first().map(|arg| (arg, arg.parse::<i64>())) {
+            None => 1,
+            Some((_, Ok(count))) if count >= 1 => count as usize,
+            Some((arg, Ok(_))) => {
+                return self.fail(1, &format!("{}: {}: loop count out of range", name, arg))
+            }
+            Some((arg, Err(_))) => {
+                return self.fail(2, &format!("{}: {}: numeric argument required", name, arg))
+            }
+        };
+
+        if state.loop_depth() == 0 {
+            return self.fail(
+                0,
+                &format!(
+                    "{}: only meaningful in a `for', `while', or `until' loop",
+                    name
+                ),
+            );
+        }
+        state.set_jump(Some(jump(count.min(state.loop_depth()))));
+    }
+
+    /// Exits the shell with the status given, taken modulo 256, or the status of the
+    /// last command. A status that isn't a number still exits, with status 2.
+    fn exit(&mut self, args: &[String], state: &mut State) {
+        let status = match args.first() {
+            None => state.status(),
+            Some(arg) => match arg.parse::<i64>() {
+                Ok(status) => (status & 0xff) as i32,
+                Err(_) => {
+                    state.write_stderr(
+                        format!("exit: {}: numeric argument required\n", arg).as_bytes(),
+                    );
+                    process::exit(2);
                 }
-                Err(err) => self.write_error(&format!("{}\n", err)),
             },
+        };
+
+        if args.len() > 1 {
+            return self.fail(1, "exit: too many arguments");
+        }
+        process::exit(status);
+    }
+
+    /// `local name[=value]...`: makes variables local to the running function, so
+    /// the caller gets its own values back when it returns.
+    fn local(&mut self, args: &[String], state: &mut State) {
+        if !state.in_function() {
+            return self.fail(1, "local: can only be used in a function");
+        }
+
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+
+            if !Variables::is_valid_name(name) {
+                self.fail(1, &format!("local: `{}': not a valid identifier", arg));
+                continue;
+            }
+
+            state.make_local(name);
+            match value {
+                Some(value) => state.vars_mut().set(name, value),
+                None => state.vars_mut().unset(name),
+            }
+        }
+    }
+
+    /// `return [n]`: leaves the running function with status `n`, or the status of
+    /// the last command.
+    fn leave_function(&mut self, args: &[String], state: &mut State) {
+        if !state.in_function() {
+            return self.fail(
+                1,
+                "return: can only `return' from a function or sourced script",
+            );
+        }
+
+        self.status = match args.first() {
+            None => state.status(),
+            Some(arg) => match arg.parse::<i64>() {
+                Ok(status) => (status & 0xff) as i32,
+                Err(_) => {
+                    self.fail(2, &format!("return: {}: numeric argument required", arg));
+                    2
+                }
+            },
+        };
+        state.set_jump(Some(Jump::Return));
+    }
+
+    /// `shift [n]`: drops the first `n` positional parameters, 1 by default. Fails
+    /// without changing them if there are fewer.
+    fn shift(&mut self, args: &[String], state: &mut State) {
+        let count = match args.first().map(|arg| (arg, arg.parse::<usize>())) {
+            None => 1,
+            Some((_, Ok(count))) => count,
+            Some((arg, Err(_))) => {
+                return self.fail(1, &format!("shift: {}: numeric argument required", arg))
+            }
+        };
+
+        if count > state.args().len() {
+            self.status = 1;
+            return;
+        }
+        let args = state.args()[count..].to_vec();
+        state.set_args(args);
+    }
+
+    /// `shopt [-s|-u] [-p] [-q] [name...]`: sets (`-s`) or unsets (`-u`) shell
+    /// options, or shows them. When only showing named options, the status tells
+    /// whether they are all set.
+    fn shopt(&mut self, args: &[String], state: &mut State) {
+        let (mut set, mut unset, mut print, mut quiet) = (false, false, false, false);
+        let mut args = args.iter().peekable();
+
+        while let Some(arg) = args.next_if(|arg| arg.len() > 1 && arg.starts_with('-')) {
+            if arg == "--" {
+                break;
+            }
+
+            for flag in arg.chars().skip(1) {
+                match flag {
+                    's' => set = true,
+                    'u' => unset = true,
+                    'p' => print = true,
+                    'q' => quiet = true,
+                    _ => {
+                        return self.fail(
+                            2,
+                            &format!(
+                            "shopt: -{}: invalid option\nshopt: usage: shopt [-pqsu] [optname ...]",
+                            flag
+                        ),
+                        )
+                    }
+                }
+            }
+        }
+
+        if set && unset {
+            return self.fail(
+                1,
+                "shopt: cannot set and unset shell options simultaneously",
+            );
+        }
+
+        let names: Vec<&String> = args.collect();
+        let format = |name: &str, enabled: bool| {
+            if print {
+                format!("shopt {} {}\n", if enabled { "-s" } else { "-u" }, name)
+            } else {
+                format!("{:<15}\t{}\n", name, if enabled { "on" } else { "off" })
+            }
+        };
+
+        if names.is_empty() {
+            // `-s` and `-u` alone list the options that are set or unset.
+            let list: String = state
+                .options()
+                .iter()
+                .filter(|(_, enabled)| (!set || *enabled) && (!unset || !*enabled))
+                .map(|(name, enabled)| format(name, enabled))
+                .collect();
+            return self.write_output(&list);
+        }
+
+        for name in names {
+            if set || unset {
+                if let Err(err) = state.options_mut().set(name, set) {
+                    self.fail(1, &format!("shopt: {}", err));
+                }
+                continue;
+            }
+
+            match state.options().iter().find(|(option, _)| option == name) {
+                Some((option, enabled)) => {
+                    if !quiet {
+                        self.write_output(&format(option, enabled));
+                    }
+                    if !enabled {
+                        self.status = 1;
+                    }
+                }
+                None => self.fail(1, &format!("shopt: {}: invalid shell option name", name)),
+            }
+        }
+    }
+
+    /// `cd [-L|-P] [dir]`: changes the working directory of the shell, and so of every
+    /// program it starts. `-L` (the default) follows `..` in the path as typed, `-P`
+    /// resolves symbolic links first.
+    fn cd(&mut self, args: &[String], state: &mut State) {
+        let mut physical = false;
+        let mut args = args.iter().peekable();
+
+        while let Some(arg) = args.next_if(|arg| arg.len() > 1 && arg.starts_with('-')) {
+            match arg.as_str() {
+                "--" => break,
+                "-L" => physical = false,
+                "-P" => physical = true,
+                _ => {
+                    return self.fail(
+                        2,
+                        &format!("cd: {}: invalid option\ncd: usage: cd [-L|-P] [dir]", arg),
+                    )
+                }
+            }
+        }
+
+        let (target, mut print) = match args.next().map(String::as_str) {
+            None => match state.vars().get("HOME") {
+                Some(home) => (home.to_string(), false),
+                None => return self.fail(1, "cd: HOME not set"),
+            },
+            Some("-") => match state.vars().get("OLDPWD") {
+                Some(old) => (old.to_string(), true),
+                None => return self.fail(1, "cd: OLDPWD not set"),
+            },
+            Some(dir) => (dir.to_string(), false),
+        };
+
+        if args.next().is_some() {
+            return self.fail(1, "cd: too many arguments");
+        }
+
+        let dir = match Self::search_cdpath(&target, state.vars().get("CDPATH")) {
+            Some(found) => {
+                print = true;
+                found
+            }
+            None => target.clone(),
+        };
+
+        let logical = if dir.starts_with('/') {
+            Self::normalize(&dir)
+        } else {
+            Self::normalize(&format!("{}/{}", state.pwd(), dir))
+        };
+
+        // A logical path may not exist physically when `..` crosses a symbolic link,
+        // the kernel's resolution is tried then, like bash does.
+        let changed = if physical {
+            env::set_current_dir(&dir)
+        } else {
+            env::set_current_dir(&logical).or_else(|_| env::set_current_dir(&dir))
+        };
+
+        if let Err(err) = changed {
+            return self.fail(1, &format!("cd: {}: {}", target, io_error_message(&err)));
+        }
+
+        let pwd = if physical || !Path::new(&logical).is_dir() {
+            env::current_dir().map_or(logical, |path| path.to_string_lossy().to_string())
+        } else {
+            logical
+        };
+
+        let old = state.pwd().to_string();
+        for (name, value) in [("OLDPWD", old.as_str()), ("PWD", pwd.as_str())] {
+            state.vars_mut().set(name, value);
+            state.vars_mut().export(name, true);
+        }
+        state.set_pwd(&pwd);
+
+        if print {
+            self.write_output(&format!("{}\n", pwd));
+        }
+    }
+
+    /// Looks a relative directory up in the colon separated `CDPATH`. Only matches
+    /// found through a non-empty entry are returned, they are printed after `cd`.
+    fn search_cdpath(dir: &str, cdpath: Option<&str>) -> Option<String> {
+        let explicit = [".", "..", "./", "../"];
+        if dir.starts_with('/') || explicit.iter().any(|p| dir == *p || dir.starts_with(p)) {
+            return None;
+        }
+
+        cdpath?
+            .split(':')
+            .filter(|base| !base.is_empty())
+            .map(|base| format!("{}/{}", base.trim_end_matches('/'), dir))
+            .find(|path| Path::new(path).is_dir())
+    }
+
+    /// Resolves `.` and `..` components of an absolute path without looking at the
+    /// filesystem.
+    fn normalize(path: &str) -> String {
+        let mut resolved = Vec::<&str>::new();
+
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    resolved.pop();
+                }
+                _ => resolved.push(part),
+            }
+        }
+
+        format!("/{}", resolved.join("/"))
+    }
+
+    /// `fg`: continues a job with the terminal handed to it and waits for it, like a
+    /// command typed at the prompt.
+    fn foreground(&mut self, spec: Option<&str>, state: &mut State) {
+        if !state.jobs().job_control() {
+            return self.fail(1, "fg: no job control");
+        }
+
+        let index = match state.jobs().find(spec) {
+            Ok(index) => index,
+            Err(err) => return self.fail(1, &format!("fg: {}", err)),
+        };
+
+        let mut job = state.jobs_mut().remove(index);
+        state.write_stdout(format!("{}\n", job.command()).as_bytes());
+        state.jobs().resume(&mut job);
+
+        self.status = job.status();
+        if job.is_stopped() {
+            let report = state.jobs_mut().suspend(job);
+            self.write_error(&format!("\n{}", report));
+        }
+    }
+
+    /// `wait`: without arguments waits for every job, otherwise for the given jobs
+    /// or process ids, returning the status of the last one.
+    fn wait(&mut self, args: &[String], state: &mut State) {
+        if args.is_empty() {
+            for id in state.jobs_mut().wait_all() {
+                self.write_error(&format!("wait: job {} stopped\n", id));
+            }
+            return;
+        }
+
+        for arg in args {
+            match state.jobs().find(Some(arg)) {
+                Ok(index) => {
+                    let mut job = state.jobs_mut().remove(index);
+                    state.jobs().wait(&mut job, false);
+
+                    self.status = job.status();
+                    if job.is_stopped() {
+                        state.jobs_mut().add(job);
+                    }
+                }
+                Err(err) if arg.starts_with('%') => self.fail(127, &format!("wait: {}", err)),
+                Err(_) => self.fail(
+                    127,
+                    &format!("wait: pid {} is not a child of this shell", arg),
+                ),
+            }
+        }
+    }
+
+    /// `kill [-s sigspec | -signum | -sigspec] pid | jobspec ...` and `kill -l`.
+    fn kill(&mut self, args: &[String], state: &State) {
+        let mut args = args.iter().peekable();
+        let mut signal = libc::SIGTERM;
+
+        match args.peek().map(|arg| arg.as_str()) {
+            Some("-l") => {
+                let names: Vec<String> = SIGNALS
+                    .iter()
+                    .map(|(name, number)| format!("{:>2}) SIG{}", number, name))
+                    .collect();
+                return self.write_output(&format!("{}\n", names.join("\n")));
+            }
+            Some("-s") => {
+                args.next();
+                let name = args.next().map_or("", String::as_str);
+                match jobs::parse_signal(name) {
+                    Some(number) => signal = number,
+                    None => {
+                        return self
+                            .fail(1, &format!("kill: {}: invalid signal specification", name))
+                    }
+                }
+            }
+            Some(arg) if arg.len() > 1 && arg.starts_with('-') => {
+                match jobs::parse_signal(&arg[1..]) {
+                    Some(number) => signal = number,
+                    None => {
+                        return self.fail(
+                            1,
+                            &format!("kill: {}: invalid signal specification", &arg[1..]),
+                        )
+                    }
+                }
+                args.next();
+            }
+            _ => (),
+        }
+
+        if args.peek().is_none() {
+            return self.fail(
+                2,
+                "kill: usage: kill [-s sigspec | -signum | -sigspec] pid | jobspec ... or kill -l",
+            );
+        }
+
+        for target in args {
+            let result = if target.starts_with('%') {
+                match state.jobs().find(Some(target)) {
+                    Ok(index) => state.jobs().kill(index, signal),
+                    Err(err) => {
+                        self.fail(1, &format!("kill: {}", err));
+                        continue;
+                    }
+                }
+            } else {
+                match target.parse::<i32>() {
+                    Ok(pid) => jobs::kill(pid, signal),
+                    Err(_) => {
+                        self.fail(
+                            1,
+                            &format!("kill: {}: arguments must be process or job IDs", target),
+                        );
+                        continue;
+                    }
+                }
+            };
+
+            if let Err(err) = result {
+                self.fail(
+                    1,
+                    &format!("kill: ({}) - {}", target, io_error_message(&err)),
+                );
+            }
         }
     }
 }
 
+/// Formats an I/O error the way shells print it, without the `(os error N)` suffix.
+pub fn io_error_message(err: &std::io::Error) -> String {
+    let message = err.to_string();
+
+    match message.find(" (os error") {
+        Some(i) => message[..i].to_string(),
+        None => message,
+    }
+}
+
 #[derive(Debug)]
 pub enum CommandType {
     Unknown(String),
-    Exit(i32),
+    Exit(Vec<String>),
     Echo(String),
     Type(String),
     Pwd(String),
-    Cd(String),
-    External { command: String, args: Vec<String> },
+    Cd(Vec<String>),
+    Jobs,
+    Fg(Option<String>),
+    Bg(Option<String>),
+    Wait(Vec<String>),
+    Kill(Vec<String>),
+    Disown(Vec<String>),
+    Export(Vec<String>),
+    Unset(Vec<String>),
+    Set(Vec<String>),
+    Shopt(Vec<String>),
+    /// `env` without a program, listing the environment it was given.
+    Env(Vec<(String, String)>),
+    Let(Vec<String>),
+    Break(Vec<String>),
+    Continue(Vec<String>),
+    Local(Vec<String>),
+    Return(Vec<String>),
+    Shift(Vec<String>),
+    /// A call of a shell function, with its arguments.
+    Function(Rc<ast::Command>, Vec<String>),
+    /// `((expr))`, succeeds when the expression is not 0.
+    Arithmetic(String),
+    /// A command that failed before running, with its status and message.
+    Failed(i32, String),
+    External {
+        command: String,
+        path: PathBuf,
+        args: Vec<String>,
+        /// The whole environment of the program.
+        env: Vec<(String, String)>,
+    },
 }
 
 impl CommandType {
@@ -109,14 +969,13 @@ impl CommandType {
 
         let resolved_args = args_list.join(" ");
 
+        // Functions come before builtins of the same name, which come before programs.
+        if let Some(body) = state.function(command) {
+            return Self::Function(body, args_list);
+        }
+
         match command {
-            "exit" => {
-                if args_list.is_empty() {
-                    Self::Exit(0)
-                } else {
-                    Self::Exit(args_list[0].parse::<i32>().unwrap())
-                }
-            }
+            "exit" => Self::Exit(args_list),
             "echo" => Self::Echo(resolved_args),
             "type" => {
                 if resolved_args.is_empty() {
@@ -125,61 +984,59 @@ impl CommandType {
                     Self::Type(resolved_args)
                 }
             }
-            "pwd" => Self::Pwd(String::from(state.pwd())),
-            "cd" => {
-                let path = if resolved_args.is_empty() {
-                    env::var("HOME").unwrap()
-                } else {
-                    resolved_args.trim_end().to_string()
-                };
-
-                let home = env::var("HOME").unwrap();
-
-                let path_parts: Vec<&str> = match path.chars().next() {
-                    Some('~') => {
-                        if let Some(p) = path.get(2..) {
-                            home.split('/').chain(p.split('/')).collect()
-                        } else {
-                            home.split('/').collect()
-                        }
-                    }
-                    Some('/') => path.split('/').collect(),
-                    Some(_) => state.pwd().split('/').chain(path.split('/')).collect(),
-                    None => unreachable!(),
-                };
-
-                let mut resolved_path = Vec::<&str>::new();
-
-                for path_part in path_parts {
-                    match path_part {
-                        "." => {}
-                        ".." => {
-                            if resolved_path.len() > 1 {
-                                resolved_path.pop();
-                            }
-                        }
-                        "" => {
-                            if resolved_path.is_empty() {
-                                resolved_path.push(path_part);
-                            }
-                        }
-                        _ => resolved_path.push(path_part),
-                    }
-                }
-
-                let path = if resolved_path.len() > 1 {
-                    resolved_path.join("/")
-                } else {
-                    "/".to_string()
-                };
-
-                Self::Cd(path)
+            "pwd" => match args_list
+                .iter()
+                .rev()
+                .find(|arg| *arg == "-L" || *arg == "-P")
+            {
+                Some(arg) if arg == "-P" => match env::current_dir() {
+                    Ok(path) => Self::Pwd(path.to_string_lossy().to_string()),
+                    Err(_) => Self::Pwd(String::from(state.pwd())),
+                },
+                _ => Self::Pwd(String::from(state.pwd())),
+            },
+            "cd" => Self::Cd(args_list),
+            "jobs" => Self::Jobs,
+            "fg" => Self::Fg(args_list.into_iter().next()),
+            "bg" => Self::Bg(args_list.into_iter().next()),
+            "wait" => Self::Wait(args_list),
+            "kill" => Self::Kill(args_list),
+            "disown" => Self::Disown(args_list),
+            "export" => Self::Export(args_list),
+            "unset" => Self::Unset(args_list),
+            "set" => Self::Set(args_list),
+            "shopt" => Self::Shopt(args_list),
+            "env" => Self::env(args_list, state.vars().environment()),
+            "let" => Self::Let(args_list),
+            "break" => Self::Break(args_list),
+            "continue" => Self::Continue(args_list),
+            "local" => Self::Local(args_list),
+            "return" => Self::Return(args_list),
+            "shift" => Self::Shift(args_list),
+            input if args_list.is_empty() && WordParser::arithmetic_command(input).is_some() => {
+                Self::Arithmetic(
+                    WordParser::arithmetic_command(input)
+                        .unwrap_or_default()
+                        .to_string(),
+                )
             }
             input => {
-                if Self::find_ext_command(command).is_some() {
+                let env = state.vars().environment();
+
+                // A name with a slash is run as it is, errors come from starting it.
+                if input.contains('/') {
                     Self::External {
-                        command: command.to_string(),
+                        command: input.to_string(),
+                        path: PathBuf::from(input),
                         args: args_list,
+                        env,
+                    }
+                } else if let Some(path) = Self::find_ext_command(input, state.vars().get("PATH")) {
+                    Self::External {
+                        command: input.to_string(),
+                        path,
+                        args: args_list,
+                        env,
                     }
                 } else {
                     Self::Unknown(input.to_owned())
@@ -188,11 +1045,71 @@ impl CommandType {
         }
     }
 
-    pub fn find_ext_command(target: &str) -> Option<&fs::DirEntry> {
-        let ext_commands = CACHE.get_or_init(Self::get_ext_commands);
-        ext_commands
+    /// `env [-i] [-u name] [name=value]... [program [args]...]`: runs a program with a
+    /// modified environment, or lists the environment without a program.
+    fn env(args: Vec<String>, mut env: Vec<(String, String)>) -> Self {
+        let mut args = args.into_iter().peekable();
+
+        while let Some(arg) = args.next_if(|arg| arg.starts_with('-') || arg.contains('=')) {
+            match arg.as_str() {
+                "-i" | "-" => env.clear(),
+                "-u" => {
+                    let name = args.next().unwrap_or_default();
+                    env.retain(|(n, _)| *n != name);
+                }
+                "--" => break,
+                _ => match arg.split_once('=') {
+                    Some((name, value)) if !arg.starts_with('-') => {
+                        env.retain(|(n, _)| n != name);
+                        env.push((name.to_string(), value.to_string()));
+                    }
+                    _ => return Self::Failed(125, format!("env: invalid option '{}'", arg)),
+                },
+            }
+        }
+
+        let command = match args.next() {
+            Some(command) => command,
+            None => return Self::Env(env),
+        };
+
+        // Like `execvp`, the default search path is used when `PATH` was removed.
+        let path_var = env
             .iter()
-            .find(|entry| entry.file_name().eq(target))
+            .find(|(name, _)| name == "PATH")
+            .map_or("/usr/bin:/bin", |(_, value)| value.as_str());
+
+        let path = if command.contains('/') {
+            Some(PathBuf::from(&command))
+        } else {
+            Self::find_ext_command(&command, Some(path_var))
+        };
+
+        match path {
+            Some(path) => Self::External {
+                command,
+                path,
+                args: args.collect(),
+                env,
+            },
+            None => Self::Failed(
+                127,
+                format!("env: '{}': No such file or directory", command),
+            ),
+        }
+    }
+
+    /// Looks an executable up in the directories of the colon separated `PATH`.
+    pub fn find_ext_command(target: &str, path: Option<&str>) -> Option<PathBuf> {
+        if target.contains('/') {
+            let path = PathBuf::from(target);
+            return Self::is_executable(&path).unwrap_or(false).then_some(path);
+        }
+
+        path?
+            .split(':')
+            .map(|dir| Path::new(if dir.is_empty() { "." } else { dir }).join(target))
+            .find(|path| path.is_file() && Self::is_executable(path).unwrap_or(false))
     }
 
     fn is_executable<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
@@ -221,3 +1138,85 @@ impl CommandType {
         commands
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Parser;
+
+    /// Runs `text`, a simple command, on the descriptors of the shell and returns its
+    /// status.
+    fn run(text: &str, state: &mut State) -> i32 {
+        let list = Parser::parse(text, true).unwrap();
+        let ast::Command::Simple(simple) = &list.items[0].and_or.first.commands[0] else {
+            panic!("not a simple command: {}", text);
+        };
+
+        let mut command = Command::new(simple);
+        let mut fds = Fds::default();
+        command.spawn(state, &mut fds, 0);
+        command.flush(state, &fds);
+        command.status()
+    }
+
+    #[test]
+    fn words_expand_before_redirections() {
+        let dir = env::temp_dir().join(format!("mini-shell-command-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut state = State::new();
+        state.vars_mut().set("dir", &dir.to_string_lossy());
+
+        // The target of the redirection is only set by expanding the words.
+        assert_eq!(run("echo ${out:=$dir/out} > $out", &mut state), 0);
+        assert_eq!(
+            fs::read_to_string(dir.join("out")).unwrap(),
+            format!("{}/out\n", dir.display())
+        );
+
+        assert_eq!(run("x=${empty:=$dir/empty} > $empty", &mut state), 0);
+        assert_eq!(fs::read_to_string(dir.join("empty")).unwrap(), "");
+        assert_eq!(
+            state.vars().get("x"),
+            Some(format!("{}/empty", dir.display()).as_str())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn assignments_before_a_command() {
+        let dir = env::temp_dir().join(format!("mini-shell-assign-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let env_file = dir.join("env");
+        let mut state = State::new();
+        state.vars_mut().set("file", &env_file.to_string_lossy());
+
+        // Without a command they set shell variables, which aren't exported.
+        assert_eq!(run("x=1 y=$x", &mut state), 0);
+        assert_eq!(state.vars().get("y"), Some("1"));
+        assert!(!state
+            .vars()
+            .environment()
+            .iter()
+            .any(|(name, _)| name == "y"));
+
+        // In front of one they are exported to it alone.
+        assert_eq!(run("x=2 z=3 env > $file", &mut state), 0);
+        let env = fs::read_to_string(&env_file).unwrap();
+        assert!(env.lines().any(|line| line == "x=2"));
+        assert!(env.lines().any(|line| line == "z=3"));
+        assert_eq!(state.vars().get("x"), Some("1"));
+        assert_eq!(state.vars().get("z"), None);
+
+        assert_eq!(run("export x", &mut state), 0);
+        assert_eq!(run("x=4 unset x", &mut state), 0);
+        assert_eq!(state.vars().get("x"), Some("1"));
+        assert!(state
+            .vars()
+            .environment()
+            .iter()
+            .any(|(name, _)| name == "x"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
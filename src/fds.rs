@@ -0,0 +1,459 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, RawFd},
+        unix::process::CommandExt,
+    },
+    process::{self, Stdio},
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::state::State;
+
+/// What an open file descriptor of a command refers to.
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// One of the shell's own standard streams.
+    Shell(i32),
+    File(Rc<File>),
+}
+
+/// Descriptors replaced by [`Fds::install`], each with a copy of what it referred
+/// to and its flags, or `None` if it was closed.
+pub type Saved = Vec<(RawFd, Option<(RawFd, libc::c_int)>)>;
+
+/// File descriptor table of a single command. It starts as the shell's standard
+/// streams and is changed by pipeline connections and redirections. Descriptors
+/// missing from the table are closed.
+#[derive(Debug, Clone)]
+pub struct Fds {
+    table: BTreeMap<i32, Target>,
+}
+
+impl Fds {
+    pub fn set(&mut self, fd: i32, target: Target) {
+        self.table.insert(fd, target);
+    }
+
+    pub fn close(&mut self, fd: i32) {
+        self.table.remove(&fd);
+    }
+
+    /// Makes `fd` a copy of `from`, like `dup2(from, fd)`. A descriptor above 2 that
+    /// isn't in the table is looked up in the shell, where a group or function with
+    /// redirections has put the ones it opened. The shell's own files are
+    /// close-on-exec and stay out of reach.
+    pub fn duplicate(&mut self, fd: i32, from: i32) -> Result<(), String> {
+        // SAFETY: only asks for the flags of the descriptor.
+        let flags = unsafe { libc::fcntl(from, libc::F_GETFD) };
+
+        let target = match self.table.get(&from) {
+            Some(target) => target.clone(),
+            None if from > 2 && flags >= 0 && flags & libc::FD_CLOEXEC == 0 => Target::Shell(from),
+            None => return Err(format!("{}: Bad file descriptor", from)),
+        };
+
+        self.table.insert(fd, target);
+        Ok(())
+    }
+
+    /// Drops the files held by the table, keeping only where the shell's own streams
+    /// go. Pipeline stages call it once the child owns its copies, so the next stage
+    /// sees end of file.
+    pub fn release(&mut self) {
+        self.table
+            .retain(|_, target| matches!(target, Target::Shell(_)));
+    }
+
+    /// Writes builtin output to the given descriptor.
+    pub fn write(&self, fd: i32, bytes: &[u8], state: &mut State) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        match self.table.get(&fd) {
+            Some(Target::Shell(1)) => state.write_stdout(bytes),
+            Some(Target::Shell(2)) => state.write_stderr(bytes),
+            Some(Target::File(file)) => {
+                let _ = file.as_ref().write_all(bytes);
+            }
+            // SAFETY: the descriptor was open when it was added to the table, a copy
+            // is written to so the shell's own stays open.
+            Some(Target::Shell(n)) => {
+                if let Ok(fd) = unsafe { BorrowedFd::borrow_raw(*n) }.try_clone_to_owned() {
+                    let _ = File::from(fd).write_all(bytes);
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// Sets up the standard streams and extra descriptors of an external command.
+    pub fn apply(&self, command: &mut process::Command) -> io::Result<()> {
+        command
+            .stdin(self.stdio(0)?)
+            .stdout(self.stdio(1)?)
+            .stderr(self.stdio(2)?);
+
+        let closed: Vec<RawFd> = (0..3).filter(|fd| !self.table.contains_key(fd)).collect();
+        let mut extra: Vec<(RawFd, RawFd)> = self
+            .table
+            .iter()
+            .filter(|(fd, _)| **fd > 2)
+            .map(|(fd, target)| match target {
+                Target::File(file) => (file.as_raw_fd(), *fd),
+                Target::Shell(n) => (*n, *fd),
+            })
+            .collect();
+
+        if closed.is_empty() && extra.is_empty() {
+            return Ok(());
+        }
+
+        // SAFETY: only async-signal-safe libc calls run between fork and exec, and the
+        // descriptors are moved out of the way first so one mapping can't clobber the
+        // source of another.
+        unsafe {
+            command.pre_exec(move || {
+                for (src, _) in extra.iter_mut() {
+                    *src = libc::fcntl(*src, libc::F_DUPFD_CLOEXEC, 64);
+                    if *src < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+
+                for (tmp, fd) in extra.iter() {
+                    if libc::dup2(*tmp, *fd) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+
+                for fd in closed.iter() {
+                    libc::close(*fd);
+                }
+
+                Ok(())
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Points the shell's own descriptors at the table, for commands the shell runs
+    /// itself, like a compound command with redirections. Returns what they referred
+    /// to before, for [`Fds::restore`].
+    pub fn install(&self) -> io::Result<Saved> {
+        let mut fds: Vec<RawFd> = self.table.keys().copied().collect();
+        fds.extend((0..3).filter(|fd| !self.table.contains_key(fd)));
+
+        let mut saved = Vec::new();
+        let mut sources = Vec::new();
+
+        // SAFETY: plain descriptor calls. Every source is copied out of the way before
+        // any descriptor is replaced, so one mapping can't clobber another.
+        unsafe {
+            for (fd, target) in self.table.iter() {
+                let src = match target {
+                    Target::Shell(n) if n == fd => continue,
+                    Target::Shell(n) => *n,
+                    Target::File(file) => file.as_raw_fd(),
+                };
+                let copy = libc::fcntl(src, libc::F_DUPFD_CLOEXEC, 10);
+                if copy < 0 {
+                    let err = io::Error::last_os_error();
+                    Self::close_all(sources.iter().map(|(_, copy)| *copy));
+                    return Err(err);
+                }
+                sources.push((*fd, copy));
+            }
+
+            for fd in fds {
+                let kept = matches!(self.table.get(&fd), Some(Target::Shell(n)) if *n == fd);
+                if !kept {
+                    let flags = libc::fcntl(fd, libc::F_GETFD);
+                    let copy = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10);
+                    saved.push((fd, (copy >= 0).then_some((copy, flags))));
+                }
+            }
+
+            for (fd, copy) in sources.iter() {
+                libc::dup2(*copy, *fd);
+            }
+            for fd in (0..3).filter(|fd| !self.table.contains_key(fd)) {
+                libc::close(fd);
+            }
+        }
+
+        Self::close_all(sources.into_iter().map(|(_, copy)| copy));
+        Ok(saved)
+    }
+
+    /// Puts back the descriptors replaced by [`Fds::install`].
+    pub fn restore(saved: Saved) {
+        // SAFETY: the copies were made by `install` and are owned by this call.
+        unsafe {
+            for (fd, copy) in saved {
+                match copy {
+                    // `dup2` clears close-on-exec, the shell's own files get it back.
+                    Some((copy, flags)) => {
+                        libc::dup2(copy, fd);
+                        libc::fcntl(fd, libc::F_SETFD, flags);
+                        libc::close(copy);
+                    }
+                    None => {
+                        libc::close(fd);
+                    }
+                }
+            }
+        }
+    }
+
+    fn close_all(fds: impl Iterator<Item = RawFd>) {
+        for fd in fds {
+            // SAFETY: closes copies owned by the caller.
+            unsafe { libc::close(fd) };
+        }
+    }
+
+    fn stdio(&self, fd: i32) -> io::Result<Stdio> {
+        Ok(match self.table.get(&fd) {
+            Some(Target::Shell(n)) if *n == fd => Stdio::inherit(),
+            // SAFETY: the shell keeps its standard streams open for its whole lifetime,
+            // and other descriptors while the command that opened them runs.
+            Some(Target::Shell(n)) => {
+                Stdio::from(unsafe { BorrowedFd::borrow_raw(*n) }.try_clone_to_owned()?)
+            }
+            Some(Target::File(file)) => Stdio::from(file.try_clone()?),
+            None => Stdio::null(),
+        })
+    }
+
+    /// Creates an unnamed file holding `contents`, to be read from the start, for
+    /// here-documents. A file rather than a pipe, so a long document can't fill it up
+    /// before the command reads it.
+    pub fn document(contents: &[u8]) -> io::Result<File> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("sh-{}-{}", process::id(), count));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let _ = fs::remove_file(&path);
+
+        file.write_all(contents)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+
+    /// Creates a kernel pipe, returning its read and write ends.
+    pub fn pipe() -> io::Result<(File, File)> {
+        let mut fds = [0; 2];
+
+        // SAFETY: `fds` has room for the two descriptors written by `pipe`, which are
+        // owned by the returned files from here on.
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            for fd in fds {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+
+            Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])))
+        }
+    }
+}
+
+impl Default for Fds {
+    fn default() -> Self {
+        Self {
+            table: (0..3).map(|fd| (fd, Target::Shell(fd))).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::operators::{Redirect, RedirectType};
+
+    fn redirect(fds: &mut Fds, op: &str, target: &str) -> Result<(), String> {
+        let redirect = Redirect::new(RedirectType::from_str(op).unwrap(), target);
+        redirect.apply(fds, &mut State::new())
+    }
+
+    /// Where `fd` of the table goes: a standard stream of the shell, a file, or
+    /// nowhere.
+    fn target(fds: &Fds, fd: i32) -> Option<&Target> {
+        fds.table.get(&fd)
+    }
+
+    fn same_file(a: Option<&Target>, b: Option<&Target>) -> bool {
+        matches!((a, b), (Some(Target::File(a)), Some(Target::File(b))) if Rc::ptr_eq(a, b))
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!("mini-shell-fds-{}-{}", process::id(), name))
+    }
+
+    fn read(path: &std::path::Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn duplicate_output() {
+        let path = temp_path("dup-out");
+        let mut fds = Fds::default();
+
+        redirect(&mut fds, "2>&", "1").unwrap();
+        assert!(matches!(target(&fds, 2), Some(Target::Shell(1))));
+
+        redirect(&mut fds, "3>", &path.to_string_lossy()).unwrap();
+        redirect(&mut fds, ">&", "3").unwrap();
+        assert!(same_file(target(&fds, 1), target(&fds, 3)));
+        assert!(matches!(target(&fds, 2), Some(Target::Shell(1))));
+
+        assert_eq!(
+            redirect(&mut fds, "1>&", "999"),
+            Err(String::from("999: Bad file descriptor"))
+        );
+        assert_eq!(
+            redirect(&mut fds, "1>&", "name"),
+            Err(String::from("name: ambiguous redirect"))
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn duplicate_input() {
+        let path = temp_path("dup-in");
+        fs::write(&path, "input").unwrap();
+        let mut fds = Fds::default();
+
+        redirect(&mut fds, "3<", &path.to_string_lossy()).unwrap();
+        redirect(&mut fds, "<&", "3").unwrap();
+        redirect(&mut fds, "4<&", "0").unwrap();
+        assert!(same_file(target(&fds, 0), target(&fds, 3)));
+        assert!(same_file(target(&fds, 4), target(&fds, 3)));
+
+        let Some(Target::File(file)) = target(&fds, 4) else {
+            unreachable!()
+        };
+        let mut text = String::new();
+        file.as_ref().read_to_string(&mut text).unwrap();
+        assert_eq!(text, "input");
+
+        assert_eq!(
+            redirect(&mut fds, "<&", "x"),
+            Err(String::from("x: ambiguous redirect"))
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn close() {
+        let mut fds = Fds::default();
+
+        redirect(&mut fds, ">&", "-").unwrap();
+        redirect(&mut fds, "<&", "-").unwrap();
+        redirect(&mut fds, "2>&", "-").unwrap();
+        assert!(fds.table.is_empty());
+
+        // Duplicating a closed descriptor fails, closing it again doesn't.
+        assert_eq!(
+            redirect(&mut fds, "2>&", "1"),
+            Err(String::from("1: Bad file descriptor"))
+        );
+        redirect(&mut fds, "1>&", "-").unwrap();
+    }
+
+    #[test]
+    fn output_and_error_to_a_file() {
+        let path = temp_path("all");
+        let mut fds = Fds::default();
+
+        redirect(&mut fds, ">&", &path.to_string_lossy()).unwrap();
+        assert!(same_file(target(&fds, 1), target(&fds, 2)));
+
+        fds.write(1, b"out\n", &mut State::new());
+        fds.write(2, b"err\n", &mut State::new());
+        assert_eq!(read(&path), "out\nerr\n");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn display() {
+        let text = |op: &str, target: &str| {
+            Redirect::new(RedirectType::from_str(op).unwrap(), target).to_string()
+        };
+
+        assert_eq!(text("<&", "3"), "<&3");
+        assert_eq!(text("0<&", "3"), "<&3");
+        assert_eq!(text("3<&", "0"), "3<&0");
+        assert_eq!(text(">&", "2"), ">&2");
+        assert_eq!(text("2>&", "1"), "2>&1");
+        assert_eq!(text("4>&", "-"), "4>&-");
+        assert_eq!(text(">&", "log"), ">&log");
+    }
+
+    #[test]
+    fn install_and_restore() {
+        // Descriptors far above what other tests open, as they share the process.
+        let (open_fd, closed_fd) = (180, 181);
+        let before = temp_path("before");
+        let after = temp_path("after");
+
+        let file = File::create(&before).unwrap();
+        // SAFETY: puts a close-on-exec copy of the file at a free descriptor.
+        assert_eq!(
+            unsafe { libc::dup3(file.as_raw_fd(), open_fd, libc::O_CLOEXEC) },
+            open_fd
+        );
+
+        let mut fds = Fds::default();
+        redirect(&mut fds, &format!("{}>", open_fd), &after.to_string_lossy()).unwrap();
+        redirect(&mut fds, &format!("{}>&", closed_fd), &open_fd.to_string()).unwrap();
+
+        let saved = fds.install().unwrap();
+        drop(fds);
+        for fd in [open_fd, closed_fd] {
+            // SAFETY: writes to descriptors installed above.
+            assert_eq!(unsafe { libc::write(fd, b"x".as_ptr().cast(), 1) }, 1);
+        }
+        Fds::restore(saved);
+
+        // SAFETY: writes to and asks about descriptors of this test only.
+        unsafe {
+            assert_eq!(libc::write(open_fd, b"y".as_ptr().cast(), 1), 1);
+            assert_eq!(libc::fcntl(open_fd, libc::F_GETFD), libc::FD_CLOEXEC);
+            assert_eq!(libc::fcntl(closed_fd, libc::F_GETFD), -1);
+            libc::close(open_fd);
+        }
+        assert_eq!(read(&after), "xx");
+        assert_eq!(read(&before), "y");
+
+        fs::remove_file(&before).unwrap();
+        fs::remove_file(&after).unwrap();
+    }
+
+    #[test]
+    fn document() {
+        let mut file = Fds::document(b"line\n").unwrap();
+        let mut text = String::new();
+        file.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "line\n");
+    }
+}
@@ -0,0 +1,263 @@
+use std::{fs, os::unix::ffi::OsStrExt, path::PathBuf};
+
+use crate::{bytes, options::Options, pattern::Pattern};
+
+/// Pathname expansion: the paths matching `pattern`, relative to the current
+/// directory unless the pattern is absolute, sorted. Quoted characters are expected
+/// escaped, as in the patterns of [`Pattern`].
+///
+/// A name starting with a dot only matches a pattern starting with a dot, unless
+/// `dotglob` is set. With `globstar`, a `**` component matches any number of
+/// directories, or any path if it is the last one.
+pub fn expand(pattern: &str, options: &Options) -> Vec<String> {
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    let mut paths = vec![if pattern.starts_with('/') {
+        String::from("/")
+    } else {
+        String::new()
+    }];
+
+    for (i, component) in components.iter().enumerate() {
+        let last = i + 1 == components.len();
+        let mut found = Vec::new();
+
+        for base in &paths {
+            if *component == "**" && options.get("globstar") {
+                if !last {
+                    found.push(base.clone());
+                }
+                walk(base, options, last, &mut found);
+            } else if !Pattern::has_special(component) {
+                let path = join(base, &Pattern::unescape(component));
+                if fs::symlink_metadata(os_path(&path)).is_ok() {
+                    found.push(path);
+                }
+            } else {
+                found.extend(
+                    matching(base, component, options)
+                        .into_iter()
+                        .map(|name| join(base, &name)),
+                );
+            }
+        }
+
+        // Only directories can lead to the next component.
+        if !last {
+            found.retain(|path| path.is_empty() || os_path(path).is_dir());
+        }
+        paths = found;
+    }
+
+    if pattern.ends_with('/') {
+        paths = paths
+            .into_iter()
+            .filter(|path| os_path(path).is_dir())
+            .map(|path| format!("{}/", path.trim_end_matches('/')))
+            .collect();
+    }
+
+    paths.retain(|path| !path.is_empty());
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Names of the entries of `dir` matching a single path component.
+fn matching(dir: &str, component: &str, options: &Options) -> Vec<String> {
+    let pattern = Pattern::new(component).ignore_case(options.get("nocaseglob"));
+    let show_hidden = options.get("dotglob") || Pattern::unescape(component).starts_with('.');
+
+    entries(dir)
+        .into_iter()
+        .filter(|name| show_hidden || !name.starts_with('.'))
+        .filter(|name| pattern.matches(name))
+        .collect()
+}
+
+/// Adds the paths below `dir`, only the directories unless `files` is set. Symbolic
+/// links to directories are not followed.
+fn walk(dir: &str, options: &Options, files: bool, found: &mut Vec<String>) {
+    for name in entries(dir) {
+        if name.starts_with('.') && !options.get("dotglob") {
+            continue;
+        }
+
+        let path = join(dir, &name);
+        if fs::symlink_metadata(os_path(&path)).is_ok_and(|meta| meta.is_dir()) {
+            found.push(path.clone());
+            walk(&path, options, files, found);
+        } else if files {
+            found.push(path);
+        }
+    }
+}
+
+/// Names of the entries of `dir`, with the bytes of names that aren't UTF-8 kept as
+/// they are.
+fn entries(dir: &str) -> Vec<String> {
+    match fs::read_dir(os_path(if dir.is_empty() { "." } else { dir })) {
+        Ok(read_dir) => read_dir
+            .flatten()
+            .map(|entry| bytes::encode(entry.file_name().as_bytes()))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The file system path of a path made by [`expand`].
+fn os_path(path: &str) -> PathBuf {
+    PathBuf::from(bytes::to_os_string(path))
+}
+
+/// Appends a name to a path built so far, where an empty path is the current
+/// directory.
+fn join(base: &str, name: &str) -> String {
+    if base.is_empty() {
+        name.to_string()
+    } else if base.ends_with('/') {
+        format!("{}{}", base, name)
+    } else {
+        format!("{}/{}", base, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, ffi::OsStr, process};
+
+    use super::*;
+    use crate::{parser::WordParser, state::State};
+
+    /// A directory of its own for a test, with a few files and directories in it.
+    fn tree(test: &str) -> String {
+        let dir = env::temp_dir().join(format!("mini-shell-glob-{}-{}", process::id(), test));
+        for path in ["sub/deep", "sub/.hidden"] {
+            fs::create_dir_all(dir.join(path)).unwrap();
+        }
+        for path in [
+            "a.txt",
+            "b.txt",
+            ".profile",
+            "sub/c.txt",
+            "sub/deep/d.txt",
+            "sub/.hidden/e.txt",
+        ] {
+            fs::write(dir.join(path), "").unwrap();
+        }
+        dir.to_string_lossy().to_string()
+    }
+
+    fn glob(dir: &str, pattern: &str, options: &Options) -> Vec<String> {
+        expand(&format!("{}/{}", dir, pattern), options)
+            .into_iter()
+            .map(|path| path[dir.len() + 1..].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn hidden_files() {
+        let dir = tree("hidden");
+        let options = Options::default();
+
+        assert_eq!(glob(&dir, "*", &options), ["a.txt", "b.txt", "sub"]);
+        assert_eq!(glob(&dir, ".*", &options), [".profile"]);
+        assert_eq!(glob(&dir, "*/", &options), ["sub/"]);
+        assert_eq!(glob(&dir, "sub/*/*.txt", &options), ["sub/deep/d.txt"]);
+        assert_eq!(glob(&dir, "sub/.*/*", &options), ["sub/.hidden/e.txt"]);
+        assert_eq!(glob(&dir, "?.t[a-x]t", &options), ["a.txt", "b.txt"]);
+        assert!(glob(&dir, "*.none", &options).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dotglob() {
+        let dir = tree("dotglob");
+        let mut options = Options::default();
+        options.set("dotglob", true).unwrap();
+
+        assert_eq!(
+            glob(&dir, "*", &options),
+            [".profile", "a.txt", "b.txt", "sub"]
+        );
+        assert_eq!(
+            glob(&dir, "sub/*/*", &options),
+            ["sub/.hidden/e.txt", "sub/deep/d.txt"]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nullglob_and_failglob() {
+        let dir = tree("nullglob");
+        let mut state = State::new();
+        let pattern = format!("{}/*.none", dir);
+
+        let fields = WordParser::expand_fields(&pattern, &mut state).unwrap();
+        assert_eq!(fields, std::slice::from_ref(&pattern));
+
+        state.options_mut().set("nullglob", true).unwrap();
+        assert!(WordParser::expand_fields(&pattern, &mut state)
+            .unwrap()
+            .is_empty());
+
+        // failglob wins over nullglob.
+        state.options_mut().set("failglob", true).unwrap();
+        let err = WordParser::expand_fields(&pattern, &mut state).unwrap_err();
+        assert_eq!(err, format!("no match: {}", pattern));
+        let fields = WordParser::expand_fields(&format!("{}/a.*", dir), &mut state).unwrap();
+        assert_eq!(fields, [format!("{}/a.txt", dir)]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn globstar() {
+        let dir = tree("globstar");
+        let mut options = Options::default();
+
+        // Without globstar `**` is the same as `*`.
+        assert_eq!(glob(&dir, "**/*.txt", &options), ["sub/c.txt"]);
+
+        options.set("globstar", true).unwrap();
+        assert_eq!(
+            glob(&dir, "**/*.txt", &options),
+            ["a.txt", "b.txt", "sub/c.txt", "sub/deep/d.txt"]
+        );
+        assert_eq!(
+            glob(&dir, "**", &options),
+            [
+                "a.txt",
+                "b.txt",
+                "sub",
+                "sub/c.txt",
+                "sub/deep",
+                "sub/deep/d.txt"
+            ]
+        );
+        assert_eq!(glob(&dir, "**/", &options), ["sub/", "sub/deep/"]);
+        assert_eq!(
+            glob(&dir, "sub/**/d*", &options),
+            ["sub/deep", "sub/deep/d.txt"]
+        );
+
+        options.set("dotglob", true).unwrap();
+        assert!(glob(&dir, "**", &options).contains(&String::from("sub/.hidden/e.txt")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_that_are_not_utf8() {
+        let dir = tree("bytes");
+        let name = OsStr::from_bytes(b"x\xff");
+        fs::write(PathBuf::from(&dir).join(name), "").unwrap();
+
+        let paths = glob(&dir, "x*", &Options::default());
+        assert_eq!(paths, [bytes::encode(b"x\xff")]);
+        assert_eq!(bytes::decode(&paths[0]), b"x\xff");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,611 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by the SIGCHLD handler, checked before the next prompt.
+static CHILD_CHANGED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigchld(_: libc::c_int) {
+    CHILD_CHANGED.store(true, Ordering::SeqCst);
+}
+
+/// Set by the SIGINT handler when Ctrl-C reaches the shell itself, which happens
+/// while it runs builtins with no job in the foreground.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether Ctrl-C was pressed since the last call.
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+/// Signals that belong to the job in the foreground rather than to the shell.
+const JOB_SIGNALS: [libc::c_int; 5] = [
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+];
+
+pub const SIGNALS: [(&str, libc::c_int); 16] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ABRT", libc::SIGABRT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("WINCH", libc::SIGWINCH),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    Stopped,
+    Done(i32),
+    /// Terminated by the signal.
+    Signaled(i32),
+}
+
+/// A pipeline started by the shell, tracked through its process group.
+#[derive(Debug)]
+pub struct Job {
+    id: usize,
+    pgid: i32,
+    processes: Vec<(i32, ProcessState)>,
+    command: String,
+    /// Set once the job being stopped was reported, so it is reported only once per
+    /// stop.
+    stop_reported: bool,
+}
+
+impl Job {
+    pub fn new(pgid: i32, pids: Vec<i32>, command: String) -> Self {
+        Self {
+            id: 0,
+            pgid,
+            processes: pids
+                .into_iter()
+                .map(|pid| (pid, ProcessState::Running))
+                .collect(),
+            command,
+            stop_reported: false,
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        !self.is_done()
+            && self
+                .processes
+                .iter()
+                .all(|(_, state)| *state != ProcessState::Running)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.processes
+            .iter()
+            .all(|(_, state)| matches!(state, ProcessState::Done(_) | ProcessState::Signaled(_)))
+    }
+
+    /// Whether a process of the job was killed by Ctrl-C.
+    pub fn is_interrupted(&self) -> bool {
+        self.processes
+            .iter()
+            .any(|(_, state)| *state == ProcessState::Signaled(libc::SIGINT))
+    }
+
+    /// Exit status of the job, taken from its last process like for a pipeline.
+    pub fn status(&self) -> i32 {
+        if self.is_stopped() {
+            return 128 + libc::SIGTSTP;
+        }
+
+        match self.processes.last() {
+            Some((_, state)) => Self::code(*state),
+            None => 0,
+        }
+    }
+
+    pub fn status_of(&self, pid: i32) -> Option<i32> {
+        self.processes
+            .iter()
+            .find(|(p, state)| {
+                *p == pid && !matches!(state, ProcessState::Running | ProcessState::Stopped)
+            })
+            .map(|(_, state)| Self::code(*state))
+    }
+
+    fn code(state: ProcessState) -> i32 {
+        match state {
+            ProcessState::Done(code) => code,
+            ProcessState::Signaled(signal) => 128 + signal,
+            ProcessState::Running | ProcessState::Stopped => 0,
+        }
+    }
+
+    fn contains(&self, pid: i32) -> bool {
+        self.processes.iter().any(|(p, _)| *p == pid)
+    }
+
+    /// Records a status reported by `waitpid`.
+    fn mark(&mut self, pid: i32, status: libc::c_int) {
+        let state = if libc::WIFSTOPPED(status) {
+            ProcessState::Stopped
+        } else if libc::WIFCONTINUED(status) {
+            ProcessState::Running
+        } else if libc::WIFSIGNALED(status) {
+            ProcessState::Signaled(libc::WTERMSIG(status))
+        } else {
+            ProcessState::Done(libc::WEXITSTATUS(status))
+        };
+
+        for (p, s) in self.processes.iter_mut() {
+            if *p == pid {
+                *s = state;
+            }
+        }
+    }
+
+    fn continued(&mut self) {
+        for (_, state) in self.processes.iter_mut() {
+            if *state == ProcessState::Stopped {
+                *state = ProcessState::Running;
+            }
+        }
+    }
+
+    fn state_name(&self) -> String {
+        if self.is_done() {
+            match self.processes.last().map(|(_, state)| *state) {
+                Some(ProcessState::Signaled(libc::SIGTERM)) => String::from("Terminated"),
+                Some(ProcessState::Signaled(libc::SIGKILL)) => String::from("Killed"),
+                Some(ProcessState::Signaled(libc::SIGINT)) => String::from("Interrupt"),
+                Some(ProcessState::Signaled(signal)) => format!("Signal {}", signal),
+                _ => match self.status() {
+                    0 => String::from("Done"),
+                    code => format!("Exit {}", code),
+                },
+            }
+        } else if self.is_stopped() {
+            String::from("Stopped")
+        } else {
+            String::from("Running")
+        }
+    }
+}
+
+/// The job table and the terminal ownership of the interactive shell.
+#[derive(Debug, Default)]
+pub struct Jobs {
+    table: Vec<Job>,
+    shell_pgid: i32,
+    job_control: bool,
+}
+
+impl Jobs {
+    /// Puts the shell in its own process group in the foreground of the terminal, and
+    /// leaves the keyboard signals to the jobs it starts.
+    pub fn enable_job_control(&mut self) {
+        // SAFETY: plain libc calls on the shell's own process and terminal, the
+        // handlers installed just store to an atomic.
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return;
+            }
+
+            for signal in JOB_SIGNALS {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+
+            // Ctrl-C is caught rather than ignored so loops of builtins can be stopped.
+            // `exec` resets caught signals, programs still get the default.
+            let handlers = [
+                (libc::SIGCHLD, on_sigchld as extern "C" fn(libc::c_int)),
+                (libc::SIGINT, on_sigint),
+            ];
+            for (signal, handler) in handlers {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = handler as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(signal, &action, std::ptr::null_mut());
+            }
+
+            self.shell_pgid = libc::getpid();
+            libc::setpgid(0, 0);
+            libc::tcsetpgrp(libc::STDIN_FILENO, self.shell_pgid);
+        }
+
+        self.job_control = true;
+    }
+
+    /// Used by forked subshells: their children stay in the subshell's process group
+    /// and the terminal is never touched. A subshell writing into a pipe whose reader
+    /// is gone dies of SIGPIPE, like a program would, instead of running on.
+    pub fn disable_job_control(&mut self) {
+        self.job_control = false;
+        self.table.clear();
+
+        // SAFETY: restores default dispositions, no handler code is involved.
+        unsafe {
+            for signal in JOB_SIGNALS {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+            libc::signal(libc::SIGCHLD, libc::SIG_DFL);
+            libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+        }
+    }
+
+    pub fn job_control(&self) -> bool {
+        self.job_control
+    }
+
+    /// Resets the signals the shell ignores to their defaults. Runs in a child right
+    /// before `exec`, ignored dispositions would otherwise be inherited.
+    pub fn reset_signals() {
+        // SAFETY: `signal` is async-signal-safe.
+        unsafe {
+            for signal in JOB_SIGNALS {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+        }
+    }
+
+    /// Adds a job to the table and returns its number.
+    pub fn add(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.table.iter().map(|j| j.id).max().unwrap_or(0) + 1;
+        }
+
+        let id = job.id;
+        self.table.push(job);
+        id
+    }
+
+    pub fn remove(&mut self, index: usize) -> Job {
+        self.table.remove(index)
+    }
+
+    /// Waits until the job finishes or stops. A foreground job gets the terminal
+    /// while it runs.
+    pub fn wait(&self, job: &mut Job, foreground: bool) {
+        let give_terminal = foreground && self.job_control;
+
+        // SAFETY: `waitpid` writes only into `status`; terminal and signal calls take
+        // plain integers.
+        unsafe {
+            if give_terminal {
+                libc::tcsetpgrp(libc::STDIN_FILENO, job.pgid);
+            }
+
+            while !job.is_done() && !job.is_stopped() {
+                let target = if self.job_control {
+                    -job.pgid
+                } else {
+                    match job
+                        .processes
+                        .iter()
+                        .find(|(_, s)| *s == ProcessState::Running)
+                    {
+                        Some((pid, _)) => *pid,
+                        None => break,
+                    }
+                };
+
+                let mut status = 0;
+                let pid = libc::waitpid(target, &mut status, libc::WUNTRACED);
+
+                if pid > 0 {
+                    job.mark(pid, status);
+                } else if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted
+                {
+                    break;
+                }
+            }
+
+            if give_terminal {
+                libc::tcsetpgrp(libc::STDIN_FILENO, self.shell_pgid);
+            }
+        }
+    }
+
+    /// Continues a stopped job in the foreground and waits for it.
+    pub fn resume(&self, job: &mut Job) {
+        job.continued();
+        let _ = kill(self.target(job), libc::SIGCONT);
+
+        self.wait(job, true);
+    }
+
+    /// `bg`: continues a stopped job, leaving it in its place in the table.
+    pub fn background(&mut self, index: usize) -> String {
+        let target = self.target(&self.table[index]);
+        let mark = Self::mark(index, self.table.len());
+        let job = &mut self.table[index];
+
+        job.continued();
+        let _ = kill(target, libc::SIGCONT);
+
+        format!("[{}]{} {} &\n", job.id, mark, job.command)
+    }
+
+    /// Adds a job that was just stopped from the terminal, and returns the line
+    /// reporting it.
+    pub fn suspend(&mut self, mut job: Job) -> String {
+        job.stop_reported = true;
+        self.add(job);
+        self.table
+            .last()
+            .map_or_else(String::new, |job| Self::format(job, '+'))
+    }
+
+    /// Sends a signal to every process of a job. A stopped job is continued as well,
+    /// otherwise it would only see the signal once resumed.
+    pub fn kill(&self, index: usize, signal: libc::c_int) -> std::io::Result<()> {
+        let job = &self.table[index];
+        kill(self.target(job), signal)?;
+
+        if job.is_stopped() && signal != libc::SIGCONT {
+            kill(self.target(job), libc::SIGCONT)?;
+        }
+
+        Ok(())
+    }
+
+    /// The process group of a job, or its first process when job control is off and
+    /// the job shares the shell's group.
+    fn target(&self, job: &Job) -> i32 {
+        if self.job_control {
+            -job.pgid
+        } else {
+            job.processes.first().map_or(job.pgid, |(pid, _)| *pid)
+        }
+    }
+
+    /// Collects state changes of background jobs without blocking.
+    pub fn reap(&mut self) {
+        if !CHILD_CHANGED.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        loop {
+            let mut status = 0;
+            // SAFETY: `waitpid` writes only into `status`.
+            let pid = unsafe {
+                libc::waitpid(
+                    -1,
+                    &mut status,
+                    libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED,
+                )
+            };
+
+            if pid <= 0 {
+                break;
+            }
+
+            if let Some(job) = self.table.iter_mut().find(|job| job.contains(pid)) {
+                job.mark(pid, status);
+            }
+        }
+    }
+
+    /// Removes finished jobs from the table and returns the lines reporting them and
+    /// the jobs stopped since the last report, like by `SIGTTIN` when reading from the
+    /// terminal in the background.
+    pub fn notifications(&mut self) -> String {
+        self.reap();
+
+        let mut report = String::new();
+        let last = self.table.len();
+
+        for (i, job) in self.table.iter_mut().enumerate() {
+            let stopped = job.is_stopped();
+            if job.is_done() || (stopped && !job.stop_reported) {
+                report.push_str(&Self::format(job, Self::mark(i, last)));
+            }
+            job.stop_reported = stopped;
+        }
+
+        self.table.retain(|job| !job.is_done());
+        report
+    }
+
+    /// The `jobs` listing.
+    pub fn list(&mut self) -> String {
+        self.reap();
+
+        let last = self.table.len();
+        self.table
+            .iter_mut()
+            .enumerate()
+            .map(|(i, job)| {
+                // Listed as stopped, it isn't reported again before the prompt.
+                job.stop_reported = job.is_stopped();
+                Self::format(job, Self::mark(i, last))
+            })
+            .collect()
+    }
+
+    pub fn format(job: &Job, mark: char) -> String {
+        format!(
+            "[{}]{}  {:<24}{}\n",
+            job.id,
+            mark,
+            job.state_name(),
+            job.command
+        )
+    }
+
+    /// `+` marks the current job, `-` the previous one.
+    fn mark(index: usize, len: usize) -> char {
+        if index + 1 == len {
+            '+'
+        } else if index + 2 == len {
+            '-'
+        } else {
+            ' '
+        }
+    }
+
+    /// Finds a job by specification: `%n`, `%+`, `%%`, `%-`, `%prefix`, or a pid.
+    /// No specification means the current job.
+    pub fn find(&self, spec: Option<&str>) -> Result<usize, String> {
+        let last = self.table.len().checked_sub(1);
+        let spec_str = spec.unwrap_or("%+");
+
+        let index = match spec_str.strip_prefix('%') {
+            Some("") | Some("+") | Some("%") => last,
+            Some("-") => last.and_then(|i| i.checked_sub(1)).or(last),
+            Some(n) if n.chars().all(|c| c.is_ascii_digit()) => {
+                let id = n.parse::<usize>().unwrap_or(0);
+                self.table.iter().position(|job| job.id == id)
+            }
+            Some(prefix) => self
+                .table
+                .iter()
+                .rposition(|job| job.command.starts_with(prefix)),
+            None => match spec_str.parse::<i32>() {
+                Ok(pid) => self.table.iter().position(|job| job.contains(pid)),
+                Err(_) => None,
+            },
+        };
+
+        match (index, spec) {
+            (Some(index), _) => Ok(index),
+            (None, None) => Err(String::from("current: no such job")),
+            (None, Some(spec)) => Err(format!("{}: no such job", spec)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// Waits for every background job, used by `wait` without arguments. Stopped
+    /// jobs can't finish and are skipped, their numbers are returned.
+    pub fn wait_all(&mut self) -> Vec<usize> {
+        let mut stopped = Vec::new();
+        let mut i = 0;
+
+        while i < self.table.len() {
+            let mut job = self.table.remove(i);
+            if !job.is_stopped() {
+                self.wait(&mut job, false);
+            }
+
+            if job.is_stopped() {
+                stopped.push(job.id);
+                self.table.insert(i, job);
+                i += 1;
+            }
+        }
+
+        stopped
+    }
+}
+
+/// Waits for a child that is not part of a job, like the shell running a command
+/// substitution, and returns its exit status.
+pub fn wait_for(pid: i32) -> i32 {
+    let mut job = Job::new(pid, vec![pid], String::new());
+
+    while !job.is_done() {
+        let mut status = 0;
+        // SAFETY: `waitpid` writes only into `status`.
+        if unsafe { libc::waitpid(pid, &mut status, 0) } == pid {
+            job.mark(pid, status);
+        } else if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+            break;
+        }
+    }
+
+    job.status()
+}
+
+/// Sends a signal to a process, or to a process group if `pid` is negative.
+pub fn kill(pid: i32, signal: libc::c_int) -> std::io::Result<()> {
+    // SAFETY: `kill` takes plain integers.
+    if unsafe { libc::kill(pid, signal) } < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Parses a signal given as a number, `NAME` or `SIGNAME`.
+pub fn parse_signal(name: &str) -> Option<libc::c_int> {
+    if let Ok(number) = name.parse::<libc::c_int>() {
+        return Some(number);
+    }
+
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+
+    SIGNALS
+        .iter()
+        .find(|(signal, _)| *signal == name)
+        .map(|(_, number)| *number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The `waitpid` status of a process stopped by `signal`.
+    fn stopped(signal: libc::c_int) -> libc::c_int {
+        (signal << 8) | 0x7f
+    }
+
+    const CONTINUED: libc::c_int = 0xffff;
+
+    #[test]
+    fn stopped_jobs_are_reported_once() {
+        let mut jobs = Jobs::default();
+        // No such process is ever waited for or signaled here.
+        jobs.add(Job::new(-2, vec![-2], String::from("cat")));
+        assert_eq!(jobs.notifications(), "");
+
+        jobs.table[0].mark(-2, stopped(libc::SIGTTIN));
+        assert_eq!(jobs.notifications(), "[1]+  Stopped                 cat\n");
+        assert_eq!(jobs.notifications(), "");
+
+        jobs.table[0].mark(-2, CONTINUED);
+        assert_eq!(jobs.notifications(), "");
+        jobs.table[0].mark(-2, stopped(libc::SIGTSTP));
+        assert_eq!(jobs.notifications(), "[1]+  Stopped                 cat\n");
+
+        // Once listed by `jobs`, a stop isn't reported again.
+        jobs.table[0].mark(-2, CONTINUED);
+        jobs.table[0].mark(-2, stopped(libc::SIGTTOU));
+        assert_eq!(jobs.list(), "[1]+  Stopped                 cat\n");
+        assert_eq!(jobs.notifications(), "");
+
+        jobs.table[0].mark(-2, 0);
+        assert_eq!(jobs.notifications(), "[1]+  Done                    cat\n");
+        assert!(jobs.table.is_empty());
+    }
+
+    #[test]
+    fn jobs_stopped_from_the_terminal_are_reported_right_away() {
+        let mut jobs = Jobs::default();
+        let mut job = Job::new(-2, vec![-2], String::from("vi"));
+        job.mark(-2, stopped(libc::SIGTSTP));
+
+        assert_eq!(jobs.suspend(job), "[1]+  Stopped                 vi\n");
+        assert_eq!(jobs.notifications(), "");
+    }
+}
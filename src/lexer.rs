@@ -0,0 +1,567 @@
+use std::{cell::Cell, iter::Peekable, str::Chars};
+
+use crate::parser::{WordParser, WordState};
+
+/// A range of bytes of the input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// The span covering both.
+    pub fn to(self, other: Span) -> Self {
+        Self::new(self.start, other.end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A word as typed, with its quotes and escapes.
+    Word(String),
+    /// A control or redirection operator. A redirection includes the file descriptor
+    /// written right before it, like `2>`.
+    Operator(String),
+    Newline,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    /// The token as shown in error messages.
+    pub fn text(&self) -> &str {
+        match &self.kind {
+            TokenKind::Word(text) | TokenKind::Operator(text) => text,
+            TokenKind::Newline => "newline",
+        }
+    }
+
+    pub fn is_operator(&self, op: &str) -> bool {
+        matches!(&self.kind, TokenKind::Operator(text) if text == op)
+    }
+}
+
+/// Characters of the input, counting the bytes read so far.
+struct Tracked<'a> {
+    chars: Chars<'a>,
+    offset: &'a Cell<usize>,
+}
+
+impl Iterator for Tracked<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+        self.offset.set(self.offset.get() + ch.len_utf8());
+        Some(ch)
+    }
+}
+
+/// The tokens of an input. Quotes and escapes are kept in the words, they are
+/// removed by [`WordParser::expand`] right before the command runs so expansions see
+/// the state of the previous commands.
+#[derive(Debug, Default)]
+pub struct Lexer {
+    pub tokens: Vec<Token>,
+    /// Bodies of the here-documents, in the order of their operators. A body is read
+    /// from the lines following the command.
+    pub here_docs: Vec<String>,
+    /// False if the input ends inside quotes, a substitution or a here-document, or
+    /// after a backslash.
+    pub complete: bool,
+    /// The closing quote or bracket missing at the end of the input, with the offset
+    /// of the one it should close.
+    pub unclosed: Option<(char, usize)>,
+}
+
+impl Lexer {
+    /// Splits the input into words, operators and newlines. With `comments`, a `#`
+    /// starting a word starts a comment up to the end of the line.
+    pub fn tokenize(text: &str, comments: bool) -> Self {
+        let offset = Cell::new(0);
+        let mut text_iter = Tracked {
+            chars: text.chars(),
+            offset: &offset,
+        }
+        .peekable();
+        let mut lexer = Self {
+            complete: true,
+            ..Self::default()
+        };
+        let mut buf = String::new();
+        let mut start = 0;
+        // Where the last quote of the word opened.
+        let mut quote_at = 0;
+        let mut arg_type = WordState::Space;
+        // Here-documents whose body starts after the next newline, as the index of
+        // their operator and whether leading tabs are stripped.
+        let mut pending = Vec::<(usize, bool)>::new();
+
+        while let Some(ch) = text_iter.next() {
+            // Peeking reads one character ahead, so the offset is taken right away.
+            let at = offset.get() - ch.len_utf8();
+            if buf.is_empty() {
+                start = at;
+            }
+
+            match arg_type {
+                WordState::Space | WordState::Raw => match ch {
+                    ' ' | '\t' => {
+                        lexer.push_word(&mut buf, start, at);
+                        arg_type = WordState::Space;
+                    }
+                    '\n' => {
+                        lexer.push_word(&mut buf, start, at);
+                        arg_type = WordState::Space;
+                        lexer.push(TokenKind::Newline, Span::new(at, at + 1));
+                        lexer.read_here_docs(&mut text_iter, &mut pending);
+                    }
+                    '#' if comments && buf.is_empty() => {
+                        while text_iter.next_if(|c| *c != '\n').is_some() {}
+                    }
+                    '\'' => {
+                        arg_type = WordState::Quote;
+                        quote_at = at;
+                        buf.push(ch);
+                    }
+                    '"' => {
+                        arg_type = WordState::DoubleQoute;
+                        quote_at = at;
+                        buf.push(ch);
+                    }
+                    // A backslash before a newline joins the lines.
+                    '\\' if text_iter.next_if_eq(&'\n').is_some() => (),
+                    '\\' => {
+                        arg_type = WordState::Raw;
+                        buf.push(ch);
+                        match text_iter.next() {
+                            Some(c) => buf.push(c),
+                            None => lexer.complete = false,
+                        }
+                    }
+                    // `((expr))` is a single word whatever the expression contains.
+                    '(' if buf.is_empty() && text_iter.peek() == Some(&'(') => {
+                        arg_type = WordState::Raw;
+                        text_iter.next();
+                        buf.push_str("((");
+
+                        match WordParser::read_group(&mut text_iter, '(', ')') {
+                            Ok(inner) => {
+                                buf.push_str(&inner);
+                                buf.push(')');
+                                buf.extend(text_iter.next_if_eq(&')'));
+                            }
+                            Err(partial) => {
+                                buf.push_str(&partial);
+                                lexer.close_missing(')', at);
+                            }
+                        }
+                    }
+                    '$' if text_iter.peek() == Some(&'\'') => {
+                        arg_type = WordState::AnsiQuote;
+                        quote_at = at;
+                        buf.push(ch);
+                        buf.extend(text_iter.next());
+                    }
+                    '$' if matches!(text_iter.peek(), Some('{') | Some('(')) => {
+                        arg_type = WordState::Raw;
+                        lexer.push_group(&mut text_iter, &mut buf, at);
+                    }
+                    '`' => {
+                        arg_type = WordState::Raw;
+                        lexer.push_backquoted(&mut text_iter, &mut buf, at);
+                    }
+                    _ => match Self::operator(ch, &mut text_iter) {
+                        Some(op) => {
+                            let end = at + op.len();
+                            let is_fd = !buf.is_empty() && buf.chars().all(|c| c.is_ascii_digit());
+
+                            if op.starts_with(['<', '>']) && is_fd {
+                                // `2>file`: the digits are the file descriptor to redirect
+                                let op = format!("{}{}", std::mem::take(&mut buf), op);
+                                lexer.push(TokenKind::Operator(op), Span::new(start, end));
+                            } else {
+                                lexer.push_word(&mut buf, start, at);
+                                lexer.push(TokenKind::Operator(op.to_string()), Span::new(at, end));
+                            }
+                            if op == "<<" || op == "<<-" {
+                                pending.push((lexer.tokens.len() - 1, op == "<<-"));
+                            }
+                            arg_type = WordState::Space;
+                        }
+                        None => {
+                            arg_type = WordState::Raw;
+                            buf.push(ch);
+                        }
+                    },
+                },
+                WordState::Quote => {
+                    if ch == '\'' {
+                        arg_type = WordState::Raw;
+                    }
+                    buf.push(ch);
+                }
+                WordState::AnsiQuote => {
+                    buf.push(ch);
+                    match ch {
+                        '\'' => arg_type = WordState::Raw,
+                        '\\' => buf.extend(text_iter.next()),
+                        _ => (),
+                    }
+                }
+                WordState::DoubleQoute => match ch {
+                    '$' if matches!(text_iter.peek(), Some('{') | Some('(')) => {
+                        lexer.push_group(&mut text_iter, &mut buf, at)
+                    }
+                    '`' => lexer.push_backquoted(&mut text_iter, &mut buf, at),
+                    '\\' if text_iter.next_if_eq(&'\n').is_some() => (),
+                    _ => {
+                        buf.push(ch);
+                        match ch {
+                            '"' => arg_type = WordState::Raw,
+                            '\\' => {
+                                if let Some(c) = text_iter.next() {
+                                    buf.push(c);
+                                }
+                            }
+                            _ => (),
+                        }
+                    }
+                },
+            }
+        }
+
+        lexer.push_word(&mut buf, start, text.len());
+        if !pending.is_empty() {
+            lexer.read_here_docs(&mut text_iter, &mut pending);
+        }
+
+        match arg_type {
+            WordState::Quote | WordState::AnsiQuote => lexer.close_missing('\'', quote_at),
+            WordState::DoubleQoute => lexer.close_missing('"', quote_at),
+            WordState::Space | WordState::Raw => (),
+        }
+
+        lexer
+    }
+
+    /// Records a quote or bracket left open at `at`. Only the first one counts, as
+    /// it takes the rest of the input.
+    fn close_missing(&mut self, close: char, at: usize) {
+        self.complete = false;
+        self.unclosed.get_or_insert((close, at));
+    }
+
+    fn push_group<I>(&mut self, text_iter: &mut Peekable<I>, buf: &mut String, at: usize)
+    where
+        I: Iterator<Item = char>,
+    {
+        let close = if text_iter.peek() == Some(&'{') {
+            '}'
+        } else {
+            ')'
+        };
+        if !WordParser::push_group(text_iter, buf) {
+            self.close_missing(close, at);
+        }
+    }
+
+    fn push_backquoted<I>(&mut self, text_iter: &mut Peekable<I>, buf: &mut String, at: usize)
+    where
+        I: Iterator<Item = char>,
+    {
+        if !WordParser::push_backquoted(text_iter, buf) {
+            self.close_missing('`', at);
+        }
+    }
+
+    fn push(&mut self, kind: TokenKind, span: Span) {
+        self.tokens.push(Token { kind, span });
+    }
+
+    fn push_word(&mut self, buf: &mut String, start: usize, end: usize) {
+        if !buf.is_empty() {
+            self.push(TokenKind::Word(std::mem::take(buf)), Span::new(start, end));
+        }
+    }
+
+    /// Reads the bodies of the pending here-documents, each up to the line made of its
+    /// delimiter. The input is incomplete if it ends before a delimiter.
+    fn read_here_docs<I>(&mut self, text_iter: &mut Peekable<I>, pending: &mut Vec<(usize, bool)>)
+    where
+        I: Iterator<Item = char>,
+    {
+        for (index, strip_tabs) in pending.drain(..) {
+            // Without a delimiter the operator is a syntax error, there is no body.
+            let delimiter = match self.tokens.get(index + 1).map(|token| &token.kind) {
+                Some(TokenKind::Word(word)) => Self::unquote(word),
+                _ => {
+                    self.here_docs.push(String::new());
+                    continue;
+                }
+            };
+            let mut body = String::new();
+            let mut found = false;
+
+            while text_iter.peek().is_some() {
+                let line: String = text_iter.by_ref().take_while(|c| *c != '\n').collect();
+                let line = if strip_tabs {
+                    line.trim_start_matches('\t')
+                } else {
+                    &line
+                };
+
+                if line == delimiter {
+                    found = true;
+                    break;
+                }
+                body.push_str(line);
+                body.push('\n');
+            }
+
+            self.complete &= found;
+            self.here_docs.push(body);
+        }
+    }
+
+    /// Removes the quotes and escapes of a word without expanding it, as done for the
+    /// delimiter of a here-document.
+    fn unquote(word: &str) -> String {
+        let mut text = String::new();
+        let mut quote: Option<char> = None;
+        let mut word_iter = word.chars();
+
+        while let Some(ch) = word_iter.next() {
+            match (ch, quote) {
+                ('\\', None) => text.extend(word_iter.next()),
+                ('\'' | '"', None) => quote = Some(ch),
+                (c, Some(q)) if c == q => quote = None,
+                _ => text.push(ch),
+            }
+        }
+
+        text
+    }
+
+    /// Reads a control or redirection operator starting with `ch`, consuming the rest
+    /// of it.
+    fn operator<I>(ch: char, text_iter: &mut Peekable<I>) -> Option<&'static str>
+    where
+        I: Iterator<Item = char>,
+    {
+        match (ch, text_iter.peek()) {
+            ('|', Some('|')) => {
+                text_iter.next();
+                Some("||")
+            }
+            ('&', Some('&')) => {
+                text_iter.next();
+                Some("&&")
+            }
+            ('&', Some('>')) => {
+                text_iter.next();
+                if text_iter.next_if_eq(&'>').is_some() {
+                    Some("&>>")
+                } else {
+                    Some("&>")
+                }
+            }
+            ('&', _) => Some("&"),
+            ('|', _) => Some("|"),
+            (';', Some(';')) => {
+                text_iter.next();
+                if text_iter.next_if_eq(&'&').is_some() {
+                    Some(";;&")
+                } else {
+                    Some(";;")
+                }
+            }
+            (';', Some('&')) => {
+                text_iter.next();
+                Some(";&")
+            }
+            (';', _) => Some(";"),
+            ('(', _) => Some("("),
+            (')', _) => Some(")"),
+            ('<', Some('&')) => {
+                text_iter.next();
+                Some("<&")
+            }
+            ('<', Some('<')) => {
+                text_iter.next();
+                match text_iter.peek() {
+                    Some('<') => {
+                        text_iter.next();
+                        Some("<<<")
+                    }
+                    Some('-') => {
+                        text_iter.next();
+                        Some("<<-")
+                    }
+                    _ => Some("<<"),
+                }
+            }
+            ('<', _) => Some("<"),
+            ('>', Some('>')) => {
+                text_iter.next();
+                Some(">>")
+            }
+            ('>', Some('&')) => {
+                text_iter.next();
+                Some(">&")
+            }
+            ('>', _) => Some(">"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tokens as their text and the part of the input their span covers.
+    fn tokens(text: &str) -> Vec<(String, &str)> {
+        Lexer::tokenize(text, true)
+            .tokens
+            .iter()
+            .map(|token| {
+                let span = &text[token.span.start..token.span.end];
+                (token.text().to_string(), span)
+            })
+            .collect()
+    }
+
+    fn pairs<'a>(expected: &[(&str, &'a str)]) -> Vec<(String, &'a str)> {
+        expected
+            .iter()
+            .map(|(text, span)| (text.to_string(), *span))
+            .collect()
+    }
+
+    #[test]
+    fn words_and_operators() {
+        assert_eq!(
+            tokens("echo  'a b'\"c\" 2>&1|cat"),
+            pairs(&[
+                ("echo", "echo"),
+                ("'a b'\"c\"", "'a b'\"c\""),
+                ("2>&", "2>&"),
+                ("1", "1"),
+                ("|", "|"),
+                ("cat", "cat"),
+            ])
+        );
+        assert_eq!(
+            tokens("a&&b||c;;&d &>>f"),
+            pairs(&[
+                ("a", "a"),
+                ("&&", "&&"),
+                ("b", "b"),
+                ("||", "||"),
+                ("c", "c"),
+                (";;&", ";;&"),
+                ("d", "d"),
+                ("&>>", "&>>"),
+                ("f", "f"),
+            ])
+        );
+    }
+
+    #[test]
+    fn words_keep_groups_whole() {
+        assert_eq!(
+            tokens("echo $(a | b) ${x:-a b} `c;d` ((1 < 2))"),
+            pairs(&[
+                ("echo", "echo"),
+                ("$(a | b)", "$(a | b)"),
+                ("${x:-a b}", "${x:-a b}"),
+                ("`c;d`", "`c;d`"),
+                ("((1 < 2))", "((1 < 2))"),
+            ])
+        );
+        // A redirection only takes the digits right before it.
+        assert_eq!(
+            tokens("a2>f 2 >g"),
+            pairs(&[
+                ("a2", "a2"),
+                (">", ">"),
+                ("f", "f"),
+                ("2", "2"),
+                (">", ">"),
+                ("g", "g"),
+            ])
+        );
+    }
+
+    #[test]
+    fn spans_count_bytes() {
+        let text = "é\tüber #note\nnext";
+        assert_eq!(
+            tokens(text),
+            pairs(&[
+                ("é", "é"),
+                ("über", "über"),
+                ("newline", "\n"),
+                ("next", "next")
+            ])
+        );
+        let lexer = Lexer::tokenize(text, true);
+        assert_eq!(lexer.tokens[1].span, Span::new(3, 8));
+        assert_eq!(lexer.tokens[3].span, Span::new(15, 19));
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(
+            tokens("a #b c\nd#e"),
+            pairs(&[("a", "a"), ("newline", "\n"), ("d#e", "d#e")])
+        );
+        let lexer = Lexer::tokenize("a #b", false);
+        assert_eq!(lexer.tokens.len(), 2);
+    }
+
+    #[test]
+    fn here_documents() {
+        let text = "cat <<EOF; cat <<-'END'\nhi $x\nEOF\n\t\tbye\n\tEND\necho";
+        let lexer = Lexer::tokenize(text, true);
+
+        assert!(lexer.complete);
+        assert_eq!(lexer.here_docs, ["hi $x\n", "bye\n"]);
+        // The bodies are skipped, the next token is placed after them.
+        let last = lexer.tokens.last().unwrap();
+        assert_eq!(last.text(), "echo");
+        assert_eq!(&text[last.span.start..last.span.end], "echo");
+
+        let lexer = Lexer::tokenize("cat <<EOF\nno end", true);
+        assert!(!lexer.complete);
+        assert_eq!(lexer.here_docs, ["no end\n"]);
+    }
+
+    #[test]
+    fn incomplete_input() {
+        let lexer = Lexer::tokenize("echo 'a\"b", true);
+        assert!(!lexer.complete);
+        assert_eq!(lexer.unclosed, Some(('\'', 5)));
+
+        let lexer = Lexer::tokenize("echo \"$(a\" b", true);
+        assert_eq!(lexer.unclosed, Some((')', 6)));
+
+        let lexer = Lexer::tokenize("echo a\\", true);
+        assert!(!lexer.complete);
+        assert_eq!(lexer.unclosed, None);
+
+        let lexer = Lexer::tokenize("echo a\\\nb", true);
+        assert!(lexer.complete);
+        assert_eq!(lexer.tokens[1].text(), "ab");
+    }
+}
@@ -1,17 +1,27 @@
+mod arith;
+mod ast;
 mod autocomplete;
+mod bytes;
 mod command;
+mod fds;
+mod glob;
+mod jobs;
+mod lexer;
 mod operators;
+mod options;
 mod parser;
+mod pattern;
 mod state;
+mod vars;
 
 use std::io::{self, stdout, Write};
 
 use termion::{event::Key, input::TermRead, raw::IntoRawMode};
 
+use ast::Parser;
 use autocomplete::TrieNode;
 use command::{CommandType, CACHE, COMMANDS};
 use operators::Operators;
-use parser::WordParser;
 use state::State;
 
 const BELL: char = '\u{0007}';
@@ -19,10 +29,14 @@ const BELL: char = '\u{0007}';
 fn main() {
     println!("Shell is starting..."); // the following line is necessary to initialize stdout properly in docker container
 
-    let stdin = &io::stdin();
-    let mut stdout = stdout().into_raw_mode().expect("Failed to enter raw mode");
-
     let mut state = State::new();
+
+    // Keyboard signals such as Ctrl-C and Ctrl-Z are meant for the job running in the
+    // foreground, not for the shell waiting on it.
+    state.jobs_mut().enable_job_control();
+
+    let stdin = io::stdin();
+    let mut stdout = stdout().into_raw_mode().expect("Failed to enter raw mode");
     let mut trie = TrieNode::default();
 
     let mut multi_tab: Option<Vec<String>> = None;
@@ -38,9 +52,10 @@ fn main() {
         }
     }
 
-    let mut history = Vec::<String>::new();
     let mut current_input = String::new();
-    let mut cursor_pos = 2;
+    // Lines entered so far of a command that goes on, like an unclosed quote or a
+    // here-document.
+    let mut lines = String::new();
 
     write!(
         stdout,
@@ -50,66 +65,46 @@ fn main() {
     )
     .unwrap();
 
-    loop {
-        let max_cols = termion::terminal_size().unwrap().0 as usize;
-        let max_rows = termion::terminal_size().unwrap().1 - 1;
-
-        let mut correct_history: Vec<&str> = vec![];
-
-        for line in history.iter() {
-            let line_rows = (line.len() as f32 / max_cols as f32).ceil() as usize;
-            for i in 0..line_rows {
-                let start = i * max_cols;
-                let end = line.len().min((i + 1) * max_cols);
-
-                correct_history.push(&line[start..end]);
-            }
-        }
-
-        let start_row: usize =
-            0isize.max(correct_history.len() as isize - max_rows as isize) as usize;
-
-        for (i, line) in correct_history[start_row..].iter().enumerate() {
-            let row = (i + 1) as u16;
-            write!(
-                stdout,
-                "{}{}{}",
-                termion::cursor::Goto(1, row),
-                termion::clear::CurrentLine,
-                line
-            )
-            .unwrap();
-        }
-
-        let input_row = (correct_history.len() + 1) as u16;
-        write!(
-            stdout,
-            "{}{}$ {}",
-            termion::cursor::Goto(1, input_row),
-            termion::clear::CurrentLine,
-            current_input,
-        )
-        .unwrap();
+    let mut keys = stdin.keys();
 
+    loop {
         stdout.flush().unwrap();
 
-        let c = stdin.keys().next().unwrap().unwrap();
+        let c = match keys.next() {
+            Some(Ok(c)) => c,
+            _ => break,
+        };
 
         if multi_tab.is_some() && c != Key::Char('\t') {
             multi_tab = None;
         }
 
+        let prompt = line_prompt(&lines, &state);
+
         match c {
             Key::Char('\t') => {
                 if let Some(words) = trie.get_completed_word(&current_input) {
                     if words.len() == 1 {
                         current_input = words[0].to_string();
-                        cursor_pos = current_input.chars().count() + 2;
+                        write!(
+                            stdout,
+                            "\r{}{}{}",
+                            termion::clear::CurrentLine,
+                            prompt,
+                            current_input
+                        )
+                        .unwrap();
                     } else {
                         match &multi_tab {
                             Some(w) => {
-                                history.push("$ ".to_string() + &current_input);
-                                history.push(w.join("  "));
+                                write!(
+                                    stdout,
+                                    "\r\n{}\r\n{}{}",
+                                    w.join("  "),
+                                    prompt,
+                                    current_input
+                                )
+                                .unwrap();
                             }
                             None => {
                                 multi_tab = Some(words.to_vec());
@@ -118,49 +113,83 @@ fn main() {
                     }
                 } else {
                     write!(stdout, "{}", BELL).unwrap();
-                    stdout.flush().unwrap();
                 }
             }
-            Key::Backspace => {
-                if cursor_pos > 2 {
-                    current_input.pop();
-                    cursor_pos -= 1;
-                }
+            Key::Backspace if !current_input.is_empty() => {
+                current_input.pop();
+                write!(stdout, "\u{8} \u{8}").unwrap();
+            }
+            // The lines of a command are edited as one: going back past the start of a
+            // line resumes the previous one.
+            Key::Backspace if !lines.is_empty() => {
+                lines.pop();
+                let start = lines.rfind('\n').map_or(0, |i| i + 1);
+                current_input = lines.split_off(start);
+
+                let prompt = line_prompt(&lines, &state);
+                write!(
+                    stdout,
+                    "\r{}{}\r{}{}",
+                    termion::clear::CurrentLine,
+                    termion::cursor::Up(1),
+                    prompt,
+                    current_input
+                )
+                .unwrap();
+            }
+            // Ctrl-C drops the command being typed.
+            Key::Ctrl('c') => {
+                lines.clear();
+                current_input.clear();
+                state.set_status(130);
+                write!(stdout, "^C\r\n$ ").unwrap();
             }
             Key::Char('\n') => {
-                let input = current_input.trim().to_string();
+                lines.push_str(&current_input);
+                current_input.clear();
 
-                history.push(format!("$ {}", current_input));
+                write!(stdout, "\r\n").unwrap();
 
-                if input.chars().count() != 0 {
-                    let words = WordParser::split(&input);
-                    let mut operators = Operators::create_queue(words, &state);
-                    operators.iter_mut().for_each(|op| op.exec(&mut state));
+                let comments = state.options().get("interactive_comments");
+                if Parser::is_incomplete(&lines, comments) {
+                    lines.push('\n');
+                    write!(stdout, "{}", line_prompt(&lines, &state)).unwrap();
+                    continue;
                 }
 
-                let output = state.flush_stdout();
-                let error = state.flush_stderr();
+                let input = std::mem::take(&mut lines).trim().to_string();
+                stdout.flush().unwrap();
 
-                for line in output.split('\n') {
-                    if !line.is_empty() {
-                        history.push(line.to_string());
-                    }
-                }
+                // Programs run on the terminal in its normal mode, so their output
+                // streams as it is produced and interactive ones can take over.
+                stdout.suspend_raw_mode().unwrap();
 
-                for line in error.split('\n') {
-                    if !line.is_empty() {
-                        history.push(line.to_string());
-                    }
+                if input.chars().count() != 0 {
+                    Operators::run(&input, &mut state);
                 }
 
-                current_input.clear();
-                cursor_pos = 2;
+                // Background jobs that finished meanwhile are reported before the prompt.
+                let report = state.jobs_mut().notifications();
+                state.write_stdout(report.as_bytes());
+
+                stdout.activate_raw_mode().unwrap();
+
+                write!(stdout, "$ ").unwrap();
             }
             Key::Char(c) => {
                 current_input.push(c);
-                cursor_pos += 1;
+                write!(stdout, "{}", c).unwrap();
             }
             _ => {}
         }
     }
 }
+
+/// The prompt of the next line, `PS2` when it continues the lines of a command.
+fn line_prompt(lines: &str, state: &State) -> String {
+    if lines.is_empty() {
+        String::from("$ ")
+    } else {
+        state.vars().get("PS2").unwrap_or("> ").to_string()
+    }
+}
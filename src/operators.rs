@@ -1,207 +1,762 @@
 use std::{
     error::Error,
-    fs::{File, OpenOptions},
-    io::Write,
+    fmt,
+    fs::OpenOptions,
+    io::{self, Read},
+    os::fd::AsRawFd,
+    process,
+    rc::Rc,
 };
 
 use crate::{
-    command::{Command, CommandType},
-    state::State,
+    arith,
+    ast::{
+        self, AndOr, CompoundCommand, Connector, List, Parser, Pipeline, SyntaxError, Terminator,
+    },
+    bytes,
+    command::{io_error_message, Command},
+    fds::{Fds, Target},
+    jobs::{self, Job},
+    parser::WordParser,
+    pattern::Pattern,
+    state::{Jump, State},
+    vars::Variables,
 };
 
 #[derive(Debug)]
-enum OutputType {
-    StdOut,
-    StdErr,
+pub enum RedirectType {
+    /// `[n]<file`
+    Input(i32),
+    /// `[n]>file`
+    Output(i32),
+    /// `[n]>>file`
+    Append(i32),
+    /// `[n]<&m`, or `[n]<&-` to close `n`
+    DuplicateInput(i32),
+    /// `[n]>&m`, or `[n]>&-` to close `n`. Without `n` and a number, `>&file` is
+    /// `&>file`.
+    DuplicateOutput(Option<i32>),
+    /// `&>file`, both stdout and stderr
+    OutputAll,
+    /// `&>>file`
+    AppendAll,
+    /// `[n]<<delimiter` and `[n]<<-delimiter`, followed by the lines of the document
+    HereDoc(i32),
+    /// `[n]<<<word`, the word and a newline
+    HereString(i32),
+}
+
+impl RedirectType {
+    pub fn from_str(op: &str) -> Result<Self, Box<dyn Error>> {
+        let digits: String = op.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let fd = if digits.is_empty() {
+            None
+        } else {
+            Some(digits.parse::<i32>()?)
+        };
+
+        match (&op[digits.len()..], fd) {
+            ("<", fd) => Ok(Self::Input(fd.unwrap_or(0))),
+            (">", fd) => Ok(Self::Output(fd.unwrap_or(1))),
+            (">>", fd) => Ok(Self::Append(fd.unwrap_or(1))),
+            ("<&", fd) => Ok(Self::DuplicateInput(fd.unwrap_or(0))),
+            (">&", fd) => Ok(Self::DuplicateOutput(fd)),
+            ("&>", None) => Ok(Self::OutputAll),
+            ("&>>", None) => Ok(Self::AppendAll),
+            ("<<", fd) | ("<<-", fd) => Ok(Self::HereDoc(fd.unwrap_or(0))),
+            ("<<<", fd) => Ok(Self::HereString(fd.unwrap_or(0))),
+            _ => Err("Failed to read operator".into()),
+        }
+    }
 }
 
 #[derive(Debug)]
-enum RedirectType {
-    Output(OutputType),
-    Input,
-    Append(OutputType),
+pub struct Redirect {
+    r_type: RedirectType,
+    /// The target, or the delimiter of a here-document.
+    path: String,
+    /// Lines of a here-document.
+    body: String,
 }
 
-impl RedirectType {
-    fn from_str(op: &str) -> Result<Self, Box<dyn Error>> {
-        let digits: String = op.chars().take_while(|c| c.is_ascii_digit()).collect();
-        let mut chars = op.get(digits.len()..).unwrap_or(op).chars();
+impl Redirect {
+    pub fn new(r_type: RedirectType, path: &str) -> Self {
+        Self {
+            r_type,
+            path: path.to_string(),
+            body: String::new(),
+        }
+    }
 
-        let first_char = chars.next();
+    pub fn here_doc(fd: i32, delimiter: &str, body: &str) -> Self {
+        Self {
+            r_type: RedirectType::HereDoc(fd),
+            path: delimiter.to_string(),
+            body: body.to_string(),
+        }
+    }
 
-        match first_char {
-            Some(c) => {
-                if !['<', '>'].contains(&c) {
-                    return Err("Failed to read operator".into());
-                }
+    /// Applies the redirection to a descriptor table. The target is expanded and opened
+    /// here rather than when the queue is built, so `false && echo x > f` leaves `f`
+    /// untouched.
+    pub fn apply(&self, fds: &mut Fds, state: &mut State) -> Result<(), String> {
+        let document = match self.r_type {
+            // A quoted delimiter keeps the document as it is.
+            RedirectType::HereDoc(fd) if self.path.contains(['\'', '"', '\\']) => {
+                Some((fd, self.body.clone()))
             }
-            None => {
-                return Err("Failed to read operator".into());
+            RedirectType::HereDoc(fd) => {
+                Some((fd, WordParser::expand_here_doc(&self.body, state)?))
             }
+            RedirectType::HereString(fd) => {
+                Some((fd, WordParser::expand(&self.path, state)? + "\n"))
+            }
+            _ => None,
+        };
+
+        if let Some((fd, document)) = document {
+            let file = Fds::document(&bytes::decode(&document)).map_err(|err| {
+                format!(
+                    "cannot create temp file for here-document: {}",
+                    io_error_message(&err)
+                )
+            })?;
+            fds.set(fd, Target::File(Rc::new(file)));
+            return Ok(());
         }
 
-        let output_type = match digits.parse::<u32>() {
-            Ok(1) => OutputType::StdOut,
-            Ok(2) => OutputType::StdErr,
-            Err(_) => OutputType::StdOut,
-            _ => {
-                unimplemented!()
+        let path = WordParser::expand(&self.path, state)?;
+        let mut options = OpenOptions::new();
+
+        match self.r_type {
+            RedirectType::Input(_) => options.read(true),
+            RedirectType::Output(_) | RedirectType::OutputAll => {
+                options.write(true).create(true).truncate(true)
+            }
+            RedirectType::DuplicateOutput(None) if path != "-" && path.parse::<i32>().is_err() => {
+                options.write(true).create(true).truncate(true)
+            }
+            RedirectType::Append(_) | RedirectType::AppendAll => options.append(true).create(true),
+            RedirectType::HereDoc(_) | RedirectType::HereString(_) => unreachable!(),
+            RedirectType::DuplicateInput(fd) | RedirectType::DuplicateOutput(Some(fd)) => {
+                return Self::duplicate(fds, fd, &path);
             }
+            RedirectType::DuplicateOutput(None) => return Self::duplicate(fds, 1, &path),
         };
 
-        match first_char {
-            Some('<') => Ok(Self::Input),
-            Some('>') => {
-                if let Some('>') = chars.next() {
-                    Ok(Self::Append(output_type))
-                } else {
-                    Ok(Self::Output(output_type))
-                }
+        let target = match options.open(&path) {
+            Ok(file) => Target::File(Rc::new(file)),
+            Err(err) => return Err(format!("{}: {}", path, io_error_message(&err))),
+        };
+
+        match self.r_type {
+            RedirectType::Input(fd) | RedirectType::Output(fd) | RedirectType::Append(fd) => {
+                fds.set(fd, target)
             }
-            Some(_) => Err("Failed to read operator".into()),
-            None => unreachable!(),
+            RedirectType::OutputAll
+            | RedirectType::AppendAll
+            | RedirectType::DuplicateOutput(None) => {
+                fds.set(1, target.clone());
+                fds.set(2, target);
+            }
+            RedirectType::DuplicateInput(_)
+            | RedirectType::DuplicateOutput(Some(_))
+            | RedirectType::HereDoc(_)
+            | RedirectType::HereString(_) => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    /// Makes `fd` a copy of the descriptor numbered `path`, or closes it for `-`.
+    fn duplicate(fds: &mut Fds, fd: i32, path: &str) -> Result<(), String> {
+        if path == "-" {
+            fds.close(fd);
+            Ok(())
+        } else if let Ok(from) = path.parse::<i32>() {
+            fds.duplicate(fd, from)
+        } else {
+            Err(format!("{}: ambiguous redirect", path))
         }
     }
 }
 
-#[derive(Debug)]
-pub struct Redirect {
-    command: Command,
-    r_type: RedirectType,
-    file: File,
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.r_type {
+            RedirectType::Input(0) => write!(f, "<{}", self.path),
+            RedirectType::Input(fd) => write!(f, "{}<{}", fd, self.path),
+            RedirectType::Output(1) => write!(f, ">{}", self.path),
+            RedirectType::Output(fd) => write!(f, "{}>{}", fd, self.path),
+            RedirectType::Append(1) => write!(f, ">>{}", self.path),
+            RedirectType::Append(fd) => write!(f, "{}>>{}", fd, self.path),
+            RedirectType::DuplicateInput(0) => write!(f, "<&{}", self.path),
+            RedirectType::DuplicateInput(fd) => write!(f, "{}<&{}", fd, self.path),
+            RedirectType::DuplicateOutput(None) => write!(f, ">&{}", self.path),
+            RedirectType::DuplicateOutput(Some(fd)) => write!(f, "{}>&{}", fd, self.path),
+            RedirectType::OutputAll => write!(f, "&>{}", self.path),
+            RedirectType::AppendAll => write!(f, "&>>{}", self.path),
+            RedirectType::HereDoc(0) => write!(f, "<<{}", self.path),
+            RedirectType::HereDoc(fd) => write!(f, "{}<<{}", fd, self.path),
+            RedirectType::HereString(0) => write!(f, "<<<{}", self.path),
+            RedirectType::HereString(fd) => write!(f, "{}<<<{}", fd, self.path),
+        }
+    }
 }
 
-impl Redirect {
-    fn new(command: Command, r_type: RedirectType, file_path: &str) -> Self {
-        let file = match r_type {
-            RedirectType::Output(_) => match File::create(file_path) {
-                Ok(f) => f,
-                Err(err) => panic!("{}", err),
-            },
-            RedirectType::Input => match File::open(file_path) {
-                Ok(f) => f,
-                Err(err) => panic!("{}", err),
-            },
-            RedirectType::Append(_) => {
-                match OpenOptions::new().append(true).create(true).open(file_path) {
-                    Ok(f) => f,
-                    Err(err) => panic!("{}", err),
+/// Runs the commands of a parsed input.
+pub struct Operators;
+
+impl Operators {
+    /// Parses an input and runs it, or reports its syntax error with status 2.
+    pub fn run(text: &str, state: &mut State) {
+        let comments = state.options().get("interactive_comments");
+
+        match Parser::parse(text, comments) {
+            Ok(list) => {
+                // A Ctrl-C from before this input doesn't stop it.
+                jobs::take_interrupt();
+                Self::exec(&list, state);
+                state.set_jump(None);
+            }
+            Err(err) => {
+                Self::report(text, &err, state);
+                state.set_status(2);
+            }
+        }
+    }
+
+    /// Prints a syntax error with the line of the input it is on and a caret under
+    /// the position it points at.
+    fn report(text: &str, err: &SyntaxError, state: &mut State) {
+        let at = err.span().start.min(text.len());
+        let line_start = text[..at].rfind('\n').map_or(0, |i| i + 1);
+        let line = text[line_start..].split('\n').next().unwrap_or_default();
+        // Tabs are kept so the caret lines up with the text above it.
+        let indent: String = text[line_start..at]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        state.write_stderr(format!("{}\n{}\n{}^\n", err, line, indent).as_bytes());
+    }
+
+    pub fn exec(list: &List, state: &mut State) {
+        for item in &list.items {
+            Self::check_interrupt(state);
+            if state.jump().is_some() {
+                return;
+            }
+
+            if item.background {
+                Self::exec_background(&item.and_or, state);
+            } else {
+                Self::exec_and_or(&item.and_or, state);
+            }
+        }
+    }
+
+    fn exec_and_or(and_or: &AndOr, state: &mut State) {
+        Self::exec_pipeline(&and_or.first, state);
+
+        for (connector, pipeline) in &and_or.rest {
+            let run = match connector {
+                Connector::And => state.status() == 0,
+                Connector::Or => state.status() != 0,
+            };
+            if run && state.jump().is_none() {
+                Self::exec_pipeline(pipeline, state);
+            }
+        }
+    }
+
+    /// A compound command alone runs in the shell itself, so the variables it sets
+    /// stay set. In a longer pipeline each compound stage runs in a copy of the shell.
+    fn exec_pipeline(pipeline: &Pipeline, state: &mut State) {
+        match pipeline.commands.as_slice() {
+            [command] => Self::exec_command(command, state),
+            commands => {
+                let status = Self::run_stages(commands, state, pipeline.to_string());
+                state.set_status(status);
+            }
+        }
+
+        if pipeline.negated && !matches!(state.jump(), Some(Jump::Interrupt | Jump::Abort)) {
+            state.set_status((state.status() == 0) as i32);
+        }
+    }
+
+    /// Runs a command on its own, in the shell process.
+    fn exec_command(command: &ast::Command, state: &mut State) {
+        match command {
+            // A subshell is forked like a pipeline stage, so it is a job of its own.
+            ast::Command::Simple(_) | ast::Command::Compound(CompoundCommand::Subshell(_), _) => {
+                let status =
+                    Self::run_stages(std::slice::from_ref(command), state, command.to_string());
+                state.set_status(status);
+            }
+            ast::Command::Compound(compound, redirects) => {
+                Self::exec_compound(compound, redirects, state)
+            }
+            ast::Command::Function { name, body } => {
+                state.set_function(name, body.clone());
+                state.set_status(0);
+            }
+        }
+    }
+
+    /// Runs the body of a function with `args` as the positional parameters. The
+    /// variables it makes `local` get their values back when it returns.
+    pub fn call_function(body: &ast::Command, args: Vec<String>, state: &mut State) {
+        // Loops of the caller can't be left from inside the function.
+        let depth = state.loop_depth();
+        state.set_loop_depth(0);
+        state.push_frame(args);
+
+        Self::exec_command(body, state);
+        if state.jump() == Some(Jump::Return) {
+            state.set_jump(None);
+        }
+
+        state.pop_frame();
+        state.set_loop_depth(depth);
+    }
+
+    /// Runs the stages of a pipeline as one foreground job, connected by kernel pipes,
+    /// and returns the status of the last one. External stages run concurrently and
+    /// stream into each other, builtins write their output into the pipe of the next
+    /// stage. External stages share a process group that gets the terminal until all
+    /// of them exit or the job is stopped, in which case it goes to the job table.
+    fn run_stages(commands: &[ast::Command], state: &mut State, text: String) -> i32 {
+        let mut tables = vec![Fds::default(); commands.len()];
+
+        for i in 1..tables.len() {
+            let (reader, writer) = Fds::pipe().expect("Failed to create pipe");
+            tables[i - 1].set(1, Target::File(Rc::new(writer)));
+            tables[i].set(0, Target::File(Rc::new(reader)));
+        }
+
+        // Stages start from the last one, so a builtin always writes into a pipe whose
+        // reader is already running, or has finished and closed it.
+        let mut statuses = vec![0; commands.len()];
+        let mut pids = vec![None; commands.len()];
+        let mut pgid = 0;
+
+        for (i, command) in commands.iter().enumerate().rev() {
+            // Every stage of a longer pipeline runs in a copy of the shell, so builtins
+            // such as `cd` or `exit` don't change the shell itself. A lone compound
+            // command is only forked when it is a subshell.
+            let forked = commands.len() > 1 || !matches!(command, ast::Command::Simple(_));
+
+            let pid = match command {
+                ast::Command::Simple(command) if !forked => {
+                    let mut stage = Command::new(command);
+                    let child = stage.spawn(state, &mut tables[i], pgid);
+                    if child.is_none() {
+                        stage.flush(state, &tables[i]);
+                    }
+                    statuses[i] = stage.status();
+                    child.map(|child| child.id() as i32)
+                }
+                command => match Self::fork_stage(command, &mut tables, i, pgid, state) {
+                    Ok(pid) => Some(pid),
+                    Err(err) => {
+                        let message = format!("fork: {}\n", io_error_message(&err));
+                        state.write_stderr(message.as_bytes());
+                        statuses[i] = 1;
+                        None
+                    }
+                },
+            };
+
+            if let Some(pid) = pid {
+                pids[i] = Some(pid);
+                if pgid == 0 {
+                    pgid = pid;
                 }
             }
-        };
+            tables[i].release();
+        }
 
-        Self {
-            command,
-            r_type,
-            file,
+        if pgid == 0 {
+            return statuses.last().copied().unwrap_or(0);
         }
+
+        let mut job = Job::new(pgid, pids.iter().flatten().copied().collect(), text);
+        state.jobs().wait(&mut job, true);
+
+        for (status, pid) in statuses.iter_mut().zip(pids) {
+            if let Some(pid) = pid {
+                *status = job.status_of(pid).unwrap_or(job.status());
+            }
+        }
+
+        // Like the shell itself got the Ctrl-C, the rest of the input is dropped.
+        if job.is_interrupted() {
+            state.set_jump(Some(Jump::Interrupt));
+        }
+
+        if job.is_stopped() {
+            let report = state.jobs_mut().suspend(job);
+            state.write_stderr(format!("\n{}", report).as_bytes());
+        }
+
+        statuses.last().copied().unwrap_or(0)
     }
 
-    fn exec(&mut self, state: &mut State) {
-        match &self.r_type {
-            RedirectType::Output(output_type) => {
-                self.command.exec(state);
-                match output_type {
-                    OutputType::StdOut => {
-                        self.file
-                            .write_all(self.command.output().unwrap_or(&String::new()).as_bytes())
-                            .unwrap();
-                        if let Some(error) = self.command.error() {
-                            state.write_stderr(error);
-                        }
+    /// Runs the stage `index` of a pipeline in a forked copy of the shell, which joins
+    /// the process group of the pipeline.
+    fn fork_stage(
+        command: &ast::Command,
+        tables: &mut [Fds],
+        index: usize,
+        pgid: i32,
+        state: &mut State,
+    ) -> io::Result<i32> {
+        Self::fork_shell(state, Some(pgid), |state| {
+            // The ends of the other pipes would keep them open.
+            for (i, fds) in tables.iter_mut().enumerate() {
+                if i != index {
+                    fds.release();
+                }
+            }
+            if tables[index].install().is_err() {
+                process::exit(1);
+            }
+            tables[index].release();
+
+            match command {
+                ast::Command::Simple(simple) => {
+                    let status = Command::new(simple).exec(state, &mut Fds::default());
+                    state.set_status(status);
+                }
+                ast::Command::Compound(compound, redirects) => {
+                    Self::exec_compound(compound, redirects, state)
+                }
+                command => Self::exec_command(command, state),
+            }
+        })
+    }
+
+    /// Forks a copy of the shell with job control turned off, which runs `child` and
+    /// exits with the status it leaves. With job control and a `pgid`, the copy joins
+    /// that process group, or leads a new one if it is 0. Returns the pid of the copy.
+    fn fork_shell<F>(state: &mut State, pgid: Option<i32>, child: F) -> io::Result<i32>
+    where
+        F: FnOnce(&mut State),
+    {
+        let pgid = pgid.filter(|_| state.jobs().job_control());
+
+        // SAFETY: the shell is single threaded, so the child gets a consistent copy of
+        // it and runs only shell code before exiting.
+        match unsafe { libc::fork() } {
+            0 => {
+                if let Some(pgid) = pgid {
+                    // SAFETY: moves the child into the process group.
+                    unsafe { libc::setpgid(0, pgid) };
+                }
+                state.jobs_mut().disable_job_control();
+
+                child(state);
+                process::exit(state.status());
+            }
+            -1 => Err(io::Error::last_os_error()),
+            pid => {
+                if let Some(pgid) = pgid {
+                    // SAFETY: also done by the child, so the group exists before
+                    // either side relies on it.
+                    unsafe { libc::setpgid(pid, if pgid == 0 { pid } else { pgid }) };
+                }
+                Ok(pid)
+            }
+        }
+    }
+
+    /// Runs a compound command in the shell process, its redirections pointing the
+    /// shell's own descriptors elsewhere meanwhile.
+    fn exec_compound(compound: &CompoundCommand, redirects: &[Redirect], state: &mut State) {
+        if redirects.is_empty() {
+            return Self::run_compound(compound, state);
+        }
+
+        let mut fds = Fds::default();
+        for redirect in redirects {
+            if let Err(err) = redirect.apply(&mut fds, state) {
+                state.write_stderr(format!("{}\n", err).as_bytes());
+                state.set_status(1);
+                return;
+            }
+        }
+
+        let saved = match fds.install() {
+            Ok(saved) => saved,
+            Err(err) => {
+                state.write_stderr(format!("{}\n", io_error_message(&err)).as_bytes());
+                state.set_status(1);
+                return;
+            }
+        };
+        drop(fds);
+
+        Self::run_compound(compound, state);
+        Fds::restore(saved);
+    }
+
+    fn run_compound(compound: &CompoundCommand, state: &mut State) {
+        match compound {
+            CompoundCommand::BraceGroup(list) => Self::exec(list, state),
+            // Already in the forked copy of the shell here.
+            CompoundCommand::Subshell(list) => Self::exec(list, state),
+            CompoundCommand::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, body) in branches {
+                    Self::exec(condition, state);
+                    if state.jump().is_some() {
+                        return;
                     }
-                    OutputType::StdErr => {
-                        self.file
-                            .write_all(self.command.error().unwrap_or(&String::new()).as_bytes())
-                            .unwrap();
-                        if let Some(output) = self.command.output() {
-                            state.write_stdout(output);
-                        }
+                    if state.status() == 0 {
+                        return Self::exec(body, state);
                     }
                 }
+
+                match otherwise {
+                    Some(otherwise) => Self::exec(otherwise, state),
+                    None => state.set_status(0),
+                }
             }
-            RedirectType::Append(output_type) => {
-                self.command.exec(state);
-                match output_type {
-                    OutputType::StdOut => {
-                        self.file
-                            .write_all(self.command.output().unwrap_or(&String::new()).as_bytes())
-                            .unwrap();
-                        if let Some(error) = self.command.error() {
-                            state.write_stderr(error);
+            CompoundCommand::While {
+                condition,
+                body,
+                until,
+            } => Self::run_loop(state, |state| {
+                Self::exec(condition, state);
+                if state.jump().is_some() || (state.status() == 0) == *until {
+                    return false;
+                }
+                Self::exec(body, state);
+                true
+            }),
+            CompoundCommand::For { name, words, body } => {
+                if !Variables::is_valid_name(name) {
+                    state.write_stderr(format!("`{}': not a valid identifier\n", name).as_bytes());
+                    state.set_status(1);
+                    return;
+                }
+
+                // Without `in`, the loop goes over the positional parameters.
+                let mut values = match words {
+                    Some(_) => Vec::new(),
+                    None => state.args().to_vec(),
+                };
+                for word in words.iter().flatten() {
+                    match WordParser::expand_words(&word.text, state) {
+                        Ok(fields) => values.extend(fields),
+                        Err(err) => {
+                            state.write_stderr(format!("{}\n", err).as_bytes());
+                            state.set_status(1);
+                            return;
                         }
                     }
-                    OutputType::StdErr => {
-                        self.file
-                            .write_all(self.command.error().unwrap_or(&String::new()).as_bytes())
-                            .unwrap();
-                        if let Some(output) = self.command.output() {
-                            state.write_stdout(output);
+                }
+
+                let mut values = values.into_iter();
+                Self::run_loop(state, |state| match values.next() {
+                    Some(value) => {
+                        state.vars_mut().set(name, &value);
+                        Self::exec(body, state);
+                        true
+                    }
+                    None => false,
+                });
+            }
+            CompoundCommand::ArithFor {
+                init,
+                condition,
+                step,
+                body,
+            } => {
+                if Self::arithmetic(init, state).is_none() {
+                    return;
+                }
+
+                let mut first = true;
+                Self::run_loop(state, |state| {
+                    if !first && Self::arithmetic(step, state).is_none() {
+                        return false;
+                    }
+                    first = false;
+
+                    if Self::arithmetic(condition, state) == Some(0) {
+                        return false;
+                    }
+                    Self::exec(body, state);
+                    true
+                });
+            }
+            CompoundCommand::Case { word, items } => {
+                let word = match WordParser::expand(&word.text, state) {
+                    Ok(word) => word,
+                    Err(err) => {
+                        state.write_stderr(format!("{}\n", err).as_bytes());
+                        state.set_status(1);
+                        return;
+                    }
+                };
+
+                let mut status = 0;
+                let mut matched = false;
+
+                for item in items {
+                    if !matched {
+                        for pattern in &item.patterns {
+                            match WordParser::expand_pattern(&pattern.text, state) {
+                                Ok(pattern) if Pattern::new(&pattern).matches(&word) => {
+                                    matched = true;
+                                    break;
+                                }
+                                Ok(_) => (),
+                                Err(err) => {
+                                    state.write_stderr(format!("{}\n", err).as_bytes());
+                                    state.set_status(1);
+                                    return;
+                                }
+                            }
                         }
                     }
+                    if !matched {
+                        continue;
+                    }
+
+                    state.set_status(0);
+                    Self::exec(&item.body, state);
+                    status = state.status();
+
+                    if state.jump().is_some() || item.terminator == Terminator::Break {
+                        break;
+                    }
+                    matched = item.terminator == Terminator::FallThrough;
                 }
+
+                state.set_status(status);
             }
-            RedirectType::Input => unimplemented!(),
         }
     }
-}
 
-#[derive(Debug)]
-pub enum Operators {
-    Pure(Command),
-    Redirect(Redirect),
-}
+    /// Runs the passes of a loop until `pass` returns false or a jump leaves the
+    /// loop. The status is the one of the last pass, or 0 if there was none.
+    fn run_loop<F>(state: &mut State, mut pass: F)
+    where
+        F: FnMut(&mut State) -> bool,
+    {
+        let depth = state.loop_depth();
+        state.set_loop_depth(depth + 1);
+        let mut status = 0;
 
-impl Operators {
-    pub fn create_queue(words: Vec<String>, state: &State) -> Vec<Self> {
-        let mut ops = Vec::<Operators>::new();
-        let mut buf = Vec::<&str>::new();
-        let mut words_iter = words.iter().enumerate();
-
-        while let Some((_, word)) = words_iter.next() {
-            if let Ok(r_type) = RedirectType::from_str(word) {
-                let command = Command::new(CommandType::parse(
-                    buf.iter().map(|el| el.to_string()).collect(),
-                    state,
-                ));
-                buf.clear();
-
-                let file_path = if let Some((_, w)) = words_iter.next() {
-                    w.as_str()
-                } else {
-                    panic!("redirection needs a path");
-                };
+        while pass(state) {
+            Self::check_interrupt(state);
+            status = state.status();
 
-                ops.push(Self::Redirect(Redirect::new(command, r_type, file_path)));
-            } else {
-                buf.push(word);
+            match state.jump() {
+                None => (),
+                Some(Jump::Break(1)) => {
+                    state.set_jump(None);
+                    break;
+                }
+                Some(Jump::Break(n)) => {
+                    state.set_jump(Some(Jump::Break(n - 1)));
+                    break;
+                }
+                Some(Jump::Continue(1)) => state.set_jump(None),
+                Some(Jump::Continue(n)) => {
+                    state.set_jump(Some(Jump::Continue(n - 1)));
+                    break;
+                }
+                Some(Jump::Return) | Some(Jump::Interrupt) | Some(Jump::Abort) => break,
             }
         }
 
-        if !buf.is_empty() {
-            let command = Command::new(CommandType::parse(
-                buf.iter().map(|el| el.to_string()).collect(),
-                state,
-            ));
-            ops.push(Self::Pure(command));
+        state.set_loop_depth(depth);
+        state.set_status(status);
+    }
+
+    /// Turns a Ctrl-C the shell got while running builtins itself into
+    /// [`Jump::Interrupt`], with status 130 like a command killed by it.
+    fn check_interrupt(state: &mut State) {
+        if jobs::take_interrupt() {
+            state.set_status(130);
+            state.set_jump(Some(Jump::Interrupt));
+        }
+    }
+
+    /// Evaluates an expression of `for ((...))`, where an empty one is 1. Errors are
+    /// reported, with status 1.
+    fn arithmetic(expr: &str, state: &mut State) -> Option<i64> {
+        if expr.is_empty() {
+            return Some(1);
         }
 
-        ops
+        match WordParser::expand(expr, state).and_then(|expr| arith::evaluate(&expr, state)) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                state.write_stderr(format!("{}\n", err).as_bytes());
+                state.set_status(1);
+                None
+            }
+        }
     }
 
-    pub fn exec(&mut self, state: &mut State) {
-        match self {
-            Self::Pure(cmd) => {
-                cmd.exec(state);
-                if let Some(output) = cmd.output() {
-                    state.write_stdout(output);
-                }
+    /// Runs the command of a `$(...)` substitution in a forked copy of the shell and
+    /// returns its output without the trailing newlines.
+    pub fn substitute(command: &str, state: &mut State) -> String {
+        let (mut reader, writer) = match Fds::pipe() {
+            Ok(pipe) => pipe,
+            Err(err) => {
+                state.write_stderr(format!("pipe: {}\n", io_error_message(&err)).as_bytes());
+                return String::new();
+            }
+        };
 
-                if let Some(error) = cmd.error() {
-                    state.write_stderr(error);
-                }
+        let forked = Self::fork_shell(state, None, |state| {
+            // SAFETY: points the child's standard output at the pipe.
+            unsafe { libc::dup2(writer.as_raw_fd(), 1) };
+            Self::run(command, state);
+        });
+        drop(writer);
+
+        match forked {
+            Ok(pid) => {
+                let mut output = Vec::new();
+                let _ = reader.read_to_end(&mut output);
+                state.set_substitution_status(jobs::wait_for(pid));
+
+                let output = bytes::encode(&output);
+                output.trim_end_matches('\n').to_string()
+            }
+            Err(err) => {
+                state.write_stderr(format!("fork: {}\n", io_error_message(&err)).as_bytes());
+                String::new()
+            }
+        }
+    }
+
+    /// Forks a copy of the shell to run the list while the shell goes back to the
+    /// prompt. With job control the copy leads its own process group, so it stays
+    /// off the terminal and can be moved with `fg` and `bg`.
+    fn exec_background(and_or: &AndOr, state: &mut State) {
+        let text = and_or.to_string();
+
+        match Self::fork_shell(state, Some(0), |state| Self::exec_and_or(and_or, state)) {
+            Ok(pid) => {
+                let id = state.jobs_mut().add(Job::new(pid, vec![pid], text));
+                state.write_stderr(format!("[{}] {}\n", id, pid).as_bytes());
+                state.set_status(0);
+            }
+            Err(err) => {
+                state.write_stderr(format!("fork: {}\n", io_error_message(&err)).as_bytes());
+                state.set_status(1);
             }
-            Self::Redirect(cmd) => cmd.exec(state),
         }
     }
 }
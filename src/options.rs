@@ -0,0 +1,46 @@
+use std::collections::BTreeMap;
+
+/// Options set with `shopt`, with their default value.
+const OPTIONS: [(&str, bool); 6] = [
+    ("dotglob", false),
+    ("failglob", false),
+    ("globstar", false),
+    ("interactive_comments", true),
+    ("nocaseglob", false),
+    ("nullglob", false),
+];
+
+/// Shell options changed with the `shopt` builtin.
+#[derive(Debug, Clone)]
+pub struct Options {
+    table: BTreeMap<&'static str, bool>,
+}
+
+impl Options {
+    pub fn get(&self, name: &str) -> bool {
+        self.table.get(name).copied().unwrap_or(false)
+    }
+
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        match self.table.get_mut(name) {
+            Some(option) => {
+                *option = enabled;
+                Ok(())
+            }
+            None => Err(format!("{}: invalid shell option name", name)),
+        }
+    }
+
+    /// Every option with its value, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        self.table.iter().map(|(name, enabled)| (*name, *enabled))
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            table: OPTIONS.into_iter().collect(),
+        }
+    }
+}
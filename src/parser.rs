@@ -1,131 +1,1267 @@
+use std::{
+    env,
+    ffi::{CStr, CString},
+    iter::{self, Peekable},
+    str::Chars,
+};
+
+use crate::{
+    arith, bytes, glob,
+    operators::Operators,
+    pattern::Pattern,
+    state::{Jump, State},
+    vars::Variables,
+};
+
+/// Parameters named by a single symbol, like `$?`.
+const SPECIAL_PARAMETERS: [char; 5] = ['?', '$', '#', '@', '*'];
+
+/// Field separators when `IFS` is unset.
+const DEFAULT_IFS: &str = " \t\n";
+
+/// Operators of `${NAME<op>word}`, longer ones first so `##` isn't read as `#`.
+const BRACE_OPERATORS: [&str; 16] = [
+    ":-", ":=", ":?", ":+", "##", "%%", "//", "/#", "/%", "-", "=", "?", "+", "#", "%", "/",
+];
+
+/// Where a character of a word stands, for the lexer and the expansions.
 #[derive(PartialEq, Eq)]
-enum WordState {
-    None,
+pub enum WordState {
     Space,
     Raw,
     Quote,
     DoubleQoute,
-    RawBackSlash,
+    /// `$'...'`, where backslash escapes are decoded
+    AnsiQuote,
+}
+
+/// A field produced by expanding a word.
+#[derive(Default)]
+struct Field {
+    text: String,
+    /// The text with its quoted characters escaped for [`Pattern`].
+    pattern: String,
+    /// Set when the field has unquoted pattern characters, for pathname expansion.
+    glob: bool,
+}
+
+/// Collects the result of expanding a word.
+struct Fields {
+    done: Vec<Field>,
+    current: Field,
+    /// Set once the current field exists, even if empty, like after `""`.
+    started: bool,
+    /// Separators for the results of unquoted expansions, `None` to keep one field.
+    ifs: Option<String>,
+}
+
+impl Fields {
+    fn new(ifs: Option<String>) -> Self {
+        Self {
+            done: Vec::new(),
+            current: Field::default(),
+            started: false,
+            ifs,
+        }
+    }
+
+    /// Adds quoted or escaped text to the current field.
+    fn push_quoted(&mut self, text: &str) {
+        self.current.text.push_str(text);
+        self.current.pattern.push_str(&Pattern::escape(text));
+        self.started = true;
+    }
+
+    /// Adds an unquoted character to the current field.
+    fn push(&mut self, ch: char) {
+        self.current.text.push(ch);
+        self.current.pattern.push(ch);
+        self.current.glob |= matches!(ch, '*' | '?' | '[');
+        self.started = true;
+    }
+
+    /// Adds the result of an unquoted expansion. Whitespace separators delimit fields
+    /// in runs and are dropped at the edges, any other separator delimits exactly one.
+    fn push_expansion(&mut self, text: &str) {
+        let ifs = self.ifs.clone().unwrap_or_default();
+
+        for ch in text.chars() {
+            if !ifs.contains(ch) {
+                self.push(ch);
+            } else if ch.is_whitespace() {
+                if self.started {
+                    self.split();
+                }
+            } else {
+                self.split();
+            }
+        }
+    }
+
+    /// Adds a quoted `"$@"`: each argument in a field of its own, the first and last
+    /// joined to the text around them. Without arguments nothing is added, so a word
+    /// made only of `"$@"` disappears.
+    fn push_arguments(&mut self, args: &[String]) {
+        if self.ifs.is_none() {
+            return self.push_quoted(&args.join(" "));
+        }
+
+        if args.is_empty() && self.current.text.is_empty() {
+            self.started = false;
+        }
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                self.split();
+            }
+            self.push_quoted(arg);
+        }
+    }
+
+    fn split(&mut self) {
+        self.done.push(std::mem::take(&mut self.current));
+        self.started = false;
+    }
+
+    fn finish(mut self) -> Vec<Field> {
+        if self.started || self.ifs.is_none() {
+            self.split();
+        }
+        self.done
+    }
+}
+
+/// A piece of a word for brace expansion: unquoted braces and commas apart from the
+/// rest of the text.
+#[derive(Clone, PartialEq, Eq)]
+enum Brace {
+    Text(String),
+    Open,
+    Comma,
+    Close,
 }
 
 pub struct WordParser;
 
 impl WordParser {
-    pub fn split(text: &str) -> Vec<String> {
-        let mut args = Vec::<String>::new();
-        let mut buf = Vec::<&str>::new();
-        let mut start_index = 0usize;
-        let mut arg_type = WordState::None;
-        let mut text_iter = text.chars().enumerate();
-
-        while let Some((i, ch)) = text_iter.next() {
-            match arg_type {
-                WordState::None => match ch {
-                    ' ' => {
-                        arg_type = WordState::Space;
-                        if !buf.is_empty() {
-                            args.push(buf.join(""));
-                            buf.clear();
+    /// Copies a `${...}` or `$(...)` expansion into the word as it is, spaces and
+    /// operators included. Returns false if it isn't closed.
+    pub fn push_group<I>(text_iter: &mut Peekable<I>, buf: &mut String) -> bool
+    where
+        I: Iterator<Item = char>,
+    {
+        let open = text_iter.next().unwrap_or_default();
+        let close = if open == '{' { '}' } else { ')' };
+        buf.push('$');
+        buf.push(open);
+
+        match Self::read_group(text_iter, open, close) {
+            Ok(inner) => {
+                buf.push_str(&inner);
+                buf.push(close);
+                true
+            }
+            Err(partial) => {
+                buf.push_str(&partial);
+                false
+            }
+        }
+    }
+
+    /// Reads up to the `close` character ending a group opened by `open`, skipping
+    /// nested groups, quotes and escaped characters. Returns what was read without the
+    /// closing character, as an error if it is missing.
+    pub fn read_group<I>(
+        text_iter: &mut Peekable<I>,
+        open: char,
+        close: char,
+    ) -> Result<String, String>
+    where
+        I: Iterator<Item = char>,
+    {
+        let mut inner = String::new();
+        let mut depth = 0;
+        let mut quote: Option<char> = None;
+
+        while let Some(ch) = text_iter.next() {
+            match (ch, quote) {
+                (c, None) if c == close && depth == 0 => return Ok(inner),
+                (c, None) if c == close => depth -= 1,
+                (c, None) if c == open => depth += 1,
+                ('\\', Some('\'')) => (),
+                ('\\', _) => {
+                    inner.push(ch);
+                    if let Some(c) = text_iter.next() {
+                        inner.push(c);
+                    }
+                    continue;
+                }
+                ('\'' | '"' | '`', None) => quote = Some(ch),
+                (c, Some(q)) if c == q => quote = None,
+                _ => (),
+            }
+            inner.push(ch);
+        }
+
+        Err(inner)
+    }
+
+    /// Copies a legacy `` `...` `` command substitution into the word.
+    pub fn push_backquoted<I>(text_iter: &mut Peekable<I>, buf: &mut String) -> bool
+    where
+        I: Iterator<Item = char>,
+    {
+        buf.push('`');
+
+        match Self::read_backquoted(text_iter) {
+            Ok(inner) => {
+                buf.push_str(&inner);
+                buf.push('`');
+                true
+            }
+            Err(partial) => {
+                buf.push_str(&partial);
+                false
+            }
+        }
+    }
+
+    /// Reads up to the closing backquote, keeping escapes as they are.
+    fn read_backquoted<I>(text_iter: &mut Peekable<I>) -> Result<String, String>
+    where
+        I: Iterator<Item = char>,
+    {
+        let mut inner = String::new();
+
+        while let Some(ch) = text_iter.next() {
+            match ch {
+                '`' => return Ok(inner),
+                '\\' => {
+                    inner.push(ch);
+                    if let Some(c) = text_iter.next() {
+                        inner.push(c);
+                    }
+                }
+                _ => inner.push(ch),
+            }
+        }
+
+        Err(inner)
+    }
+
+    /// The expression of an arithmetic command word `((expr))`.
+    pub fn arithmetic_command(word: &str) -> Option<&str> {
+        word.strip_prefix("((")?.strip_suffix("))")
+    }
+
+    /// Splits an assignment word `NAME=value` into its name and its raw value. The
+    /// name must not be quoted, `"FOO"=bar` is a plain word.
+    pub fn assignment(word: &str) -> Option<(&str, &str)> {
+        let (name, value) = word.split_once('=')?;
+
+        if Variables::is_valid_name(name) {
+            Some((name, value))
+        } else {
+            None
+        }
+    }
+
+    /// Brace expansion, done before the other expansions: `a{b,c}d` becomes `abd acd`,
+    /// `{1..10..2}` and `{a..e}` become sequences. Numbers are zero-padded when either
+    /// end has a leading zero. Braces that don't form a valid expansion, and quoted
+    /// or escaped ones, are kept as they are.
+    pub fn expand_braces(word: &str) -> Vec<String> {
+        Self::brace_expansion(&Self::brace_pieces(word))
+    }
+
+    fn brace_pieces(word: &str) -> Vec<Brace> {
+        let mut pieces = Vec::new();
+        let mut buf = String::new();
+        let mut word_iter = word.chars().peekable();
+
+        while let Some(ch) = word_iter.next() {
+            let piece = match ch {
+                '{' => Brace::Open,
+                ',' => Brace::Comma,
+                '}' => Brace::Close,
+                '\\' => {
+                    buf.push(ch);
+                    buf.extend(word_iter.next());
+                    continue;
+                }
+                '\'' | '"' => {
+                    // Backslashes escape inside double quotes and `$'...'`.
+                    let escapes = ch == '"' || buf.ends_with('$');
+                    buf.push(ch);
+                    while let Some(c) = word_iter.next() {
+                        buf.push(c);
+                        match c {
+                            c if c == ch => break,
+                            '\\' if escapes => buf.extend(word_iter.next()),
+                            _ => (),
                         }
                     }
-                    '\'' => {
-                        arg_type = WordState::Quote;
-                        start_index = i + 1;
+                    continue;
+                }
+                '$' if matches!(word_iter.peek(), Some('{') | Some('(')) => {
+                    Self::push_group(&mut word_iter, &mut buf);
+                    continue;
+                }
+                '`' => {
+                    Self::push_backquoted(&mut word_iter, &mut buf);
+                    continue;
+                }
+                _ => {
+                    buf.push(ch);
+                    continue;
+                }
+            };
+
+            if !buf.is_empty() {
+                pieces.push(Brace::Text(std::mem::take(&mut buf)));
+            }
+            pieces.push(piece);
+        }
+
+        if !buf.is_empty() {
+            pieces.push(Brace::Text(buf));
+        }
+
+        pieces
+    }
+
+    /// Expands the first valid brace expression, then the ones in its alternatives and
+    /// in the rest of the word.
+    fn brace_expansion(pieces: &[Brace]) -> Vec<String> {
+        for open in (0..pieces.len()).filter(|i| pieces[*i] == Brace::Open) {
+            let (close, alternatives) = match Self::brace_alternatives(&pieces[open + 1..]) {
+                Some((close, alternatives)) => (open + 1 + close, alternatives),
+                None => continue,
+            };
+
+            let preamble = Self::brace_text(&pieces[..open]);
+            let postscripts = Self::brace_expansion(&pieces[close + 1..]);
+            let mut words = Vec::new();
+
+            for alternative in alternatives {
+                for middle in Self::brace_expansion(&alternative) {
+                    for postscript in &postscripts {
+                        words.push(format!("{}{}{}", preamble, middle, postscript));
                     }
-                    '"' => {
-                        arg_type = WordState::DoubleQoute;
-                        start_index = i + 1;
+                }
+            }
+
+            return words;
+        }
+
+        vec![Self::brace_text(pieces)]
+    }
+
+    /// Reads the inside of a brace expression, returning the index of its closing
+    /// brace and its alternatives. Without a comma, the inside must be a sequence.
+    fn brace_alternatives(pieces: &[Brace]) -> Option<(usize, Vec<Vec<Brace>>)> {
+        let mut alternatives = vec![Vec::new()];
+        let mut depth = 0;
+
+        for (i, piece) in pieces.iter().enumerate() {
+            match piece {
+                Brace::Close if depth == 0 => {
+                    if alternatives.len() > 1 {
+                        return Some((i, alternatives));
                     }
-                    '\\' => {
-                        arg_type = WordState::RawBackSlash;
+
+                    let sequence = match alternatives[0].as_slice() {
+                        [Brace::Text(text)] => Self::brace_sequence(text)?,
+                        _ => return None,
+                    };
+                    let sequence = sequence.into_iter().map(|item| vec![Brace::Text(item)]);
+                    return Some((i, sequence.collect()));
+                }
+                Brace::Comma if depth == 0 => {
+                    alternatives.push(Vec::new());
+                    continue;
+                }
+                Brace::Open => depth += 1,
+                Brace::Close => depth -= 1,
+                _ => (),
+            }
+            alternatives.last_mut()?.push(piece.clone());
+        }
+
+        None
+    }
+
+    /// The items of `x..y` or `x..y..step`, between integers or single letters.
+    fn brace_sequence(text: &str) -> Option<Vec<String>> {
+        let parts: Vec<&str> = text.split("..").collect();
+        let step = match parts.len() {
+            2 => 1,
+            3 => parts[2].parse::<i64>().ok()?.unsigned_abs().max(1),
+            _ => return None,
+        };
+        let (from, to) = (parts[0], parts[1]);
+
+        if let (Ok(start), Ok(end)) = (from.parse::<i64>(), to.parse::<i64>()) {
+            let padded = |n: &str| {
+                n.trim_start_matches('-').len() > 1 && n.trim_start_matches('-').starts_with('0')
+            };
+            let width = if padded(from) || padded(to) {
+                from.len().max(to.len())
+            } else {
+                0
+            };
+
+            let items = Self::sequence_range(start, end, step);
+            return Some(
+                items
+                    .map(|n| format!("{:0width$}", n, width = width))
+                    .collect(),
+            );
+        }
+
+        let letter = |s: &str| {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii_alphabetic() => Some(c as i64),
+                _ => None,
+            }
+        };
+        let (start, end) = (letter(from)?, letter(to)?);
+
+        Some(
+            Self::sequence_range(start, end, step)
+                .filter_map(|c| char::from_u32(c as u32))
+                // `{Z..a}` goes through punctuation, which must stay literal.
+                .map(|c| {
+                    if c.is_ascii_alphabetic() {
+                        c.to_string()
+                    } else {
+                        format!("\\{}", c)
                     }
-                    _ => {
-                        arg_type = WordState::Raw;
-                        start_index = i;
+                })
+                .collect(),
+        )
+    }
+
+    /// From `start` to `end` included, going down when `end` is lower.
+    fn sequence_range(start: i64, end: i64, step: u64) -> impl Iterator<Item = i64> {
+        let count = start.abs_diff(end) / step;
+        let step = if end < start {
+            -(step as i64)
+        } else {
+            step as i64
+        };
+
+        (0..=count).map(move |i| start + i as i64 * step)
+    }
+
+    fn brace_text(pieces: &[Brace]) -> String {
+        pieces
+            .iter()
+            .map(|piece| match piece {
+                Brace::Text(text) => text.as_str(),
+                Brace::Open => "{",
+                Brace::Comma => ",",
+                Brace::Close => "}",
+            })
+            .collect()
+    }
+
+    /// Expands a word produced by the [`Lexer`](crate::lexer::Lexer) and removes its
+    /// quotes, keeping it a single word.
+    pub fn expand(word: &str, state: &mut State) -> Result<String, String> {
+        let mut fields = Self::expand_word(word, state, false, false)?;
+        Ok(fields.pop().map(|field| field.text).unwrap_or_default())
+    }
+
+    /// Expands the value of a `NAME=value` assignment, where a tilde is also expanded
+    /// after each `:`, as in `PATH=~/bin:~/.local/bin`.
+    pub fn expand_assignment(value: &str, state: &mut State) -> Result<String, String> {
+        let mut fields = Self::expand_word(value, state, false, true)?;
+        Ok(fields.pop().map(|field| field.text).unwrap_or_default())
+    }
+
+    /// Expands a word used as a pattern. Quoted and escaped characters are escaped in
+    /// the result, so they match only themselves.
+    pub fn expand_pattern(word: &str, state: &mut State) -> Result<String, String> {
+        let mut fields = Self::expand_word(word, state, false, false)?;
+        Ok(fields.pop().map(|field| field.pattern).unwrap_or_default())
+    }
+
+    /// Expands a command word into fields: the results of unquoted expansions are
+    /// split at the characters of `IFS`, and a word that expands to nothing unquoted
+    /// disappears. Fields with unquoted pattern characters are replaced by the
+    /// pathnames they match.
+    pub fn expand_fields(word: &str, state: &mut State) -> Result<Vec<String>, String> {
+        let mut result = Vec::new();
+
+        // Words shaped like assignments, such as the arguments of `export`, get their
+        // tildes expanded like assignments.
+        let assignment = Self::assignment(word).is_some();
+
+        for field in Self::expand_word(word, state, true, assignment)? {
+            if !field.glob {
+                result.push(field.text);
+                continue;
+            }
+
+            let paths = glob::expand(&field.pattern, state.options());
+            if !paths.is_empty() {
+                result.extend(paths);
+            } else if state.options().get("failglob") {
+                return Err(format!("no match: {}", word));
+            } else if !state.options().get("nullglob") {
+                result.push(field.text);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Brace expansion followed by [`WordParser::expand_fields`] on each result, as
+    /// done for the arguments of a command and the words of `for`.
+    pub fn expand_words(word: &str, state: &mut State) -> Result<Vec<String>, String> {
+        let mut result = Vec::new();
+        for word in Self::expand_braces(word) {
+            result.extend(Self::expand_fields(&word, state)?);
+        }
+        Ok(result)
+    }
+
+    /// Expands a word, splitting the results of unquoted expansions into fields if
+    /// `split` is set.
+    fn expand_word(
+        word: &str,
+        state: &mut State,
+        split: bool,
+        assignment: bool,
+    ) -> Result<Vec<Field>, String> {
+        let ifs = split.then(|| state.vars().get("IFS").unwrap_or(DEFAULT_IFS).to_string());
+        let mut fields = Fields::new(ifs);
+        let mut arg_type = WordState::Raw;
+        let mut word_iter = word.chars().peekable();
+        // A tilde is expanded at the start of the word, and after `=` and `:` in
+        // assignments.
+        let mut tilde = true;
+        // Decoded bytes of a `$'...'` string, which escapes can make invalid UTF-8.
+        // They are kept as they are through `bytes::encode`.
+        let mut ansi = Vec::<u8>::new();
+
+        while let Some(ch) = word_iter.next() {
+            let at_tilde = std::mem::replace(&mut tilde, false);
+
+            match arg_type {
+                WordState::Space | WordState::Raw => match ch {
+                    '~' if at_tilde => {
+                        match Self::expand_tilde(&mut word_iter, state, assignment) {
+                            Some(home) => fields.push_quoted(&home),
+                            None => fields.push(ch),
+                        }
                     }
-                },
-                WordState::Space => match ch {
-                    ' ' => (),
                     '\'' => {
                         arg_type = WordState::Quote;
-                        start_index = i + 1;
+                        fields.push_quoted("");
                     }
                     '"' => {
                         arg_type = WordState::DoubleQoute;
-                        start_index = i + 1;
+                        fields.push_quoted("");
                     }
                     '\\' => {
-                        arg_type = WordState::RawBackSlash;
+                        if let Some(c) = word_iter.next() {
+                            fields.push_quoted(&c.to_string());
+                        }
+                    }
+                    '$' if word_iter.next_if_eq(&'\'').is_some() => {
+                        arg_type = WordState::AnsiQuote;
+                        fields.push_quoted("");
                     }
+                    // `$"..."` would be translated in the current locale, there are no
+                    // translations so it is a plain double-quoted string.
+                    '$' if word_iter.peek() == Some(&'"') => (),
+                    '$' => fields.push_expansion(&Self::expand_dollar(&mut word_iter, state)?),
+                    '`' => fields.push_expansion(&Self::expand_backquoted(&mut word_iter, state)?),
                     _ => {
-                        arg_type = WordState::Raw;
-                        start_index = i;
+                        tilde = assignment && (ch == ':' || ch == '=');
+                        fields.push(ch);
                     }
                 },
-                WordState::Raw => {
-                    static DELIM_CHARS: [char; 4] = ['\'', ' ', '"', '\\'];
-                    if DELIM_CHARS.contains(&ch) {
-                        buf.push(&text[start_index..i]);
-                    }
+                WordState::AnsiQuote => match ch {
+                    '\'' => {
+                        arg_type = WordState::Raw;
 
-                    match ch {
-                        ' ' => {
-                            arg_type = WordState::Space;
-                            args.push(buf.join(""));
-                            buf.clear();
-                        }
-                        '\'' => {
-                            arg_type = WordState::Quote;
-                            start_index = i + 1;
-                        }
-                        '"' => {
-                            arg_type = WordState::DoubleQoute;
-                            start_index = i + 1;
-                        }
-                        '\\' => {
-                            arg_type = WordState::RawBackSlash;
+                        // A NUL ends the string, like it would end a C string.
+                        if let Some(end) = ansi.iter().position(|b| *b == 0) {
+                            ansi.truncate(end);
                         }
-                        _ => (),
+                        fields.push_quoted(&bytes::encode(&ansi));
+                        ansi.clear();
                     }
-                }
+                    '\\' => Self::ansi_c_escape(&mut word_iter, &mut ansi),
+                    _ => ansi.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes()),
+                },
                 WordState::Quote => {
                     if ch == '\'' {
-                        arg_type = WordState::None;
-                        buf.push(&text[start_index..i]);
+                        arg_type = WordState::Raw;
+                    } else {
+                        fields.push_quoted(&ch.to_string());
                     }
                 }
                 WordState::DoubleQoute => match ch {
-                    '"' => {
-                        arg_type = WordState::None;
-                        buf.push(&text[start_index..i]);
-                    }
+                    '"' => arg_type = WordState::Raw,
                     '\\' => {
-                        if let Some((_, c)) = text_iter.next() {
-                            static ESC_CHARS: [char; 3] = ['\\', '$', '"'];
-                            if ESC_CHARS.contains(&c) {
-                                buf.push(&text[start_index..i]);
-                                start_index = i + 1;
+                        static ESC_CHARS: [char; 4] = ['\\', '$', '"', '`'];
+                        match word_iter.peek() {
+                            Some(c) if ESC_CHARS.contains(c) => {
+                                fields.push_quoted(&c.to_string());
+                                word_iter.next();
                             }
+                            _ => fields.push_quoted("\\"),
                         }
                     }
-                    _ => (),
+                    '$' if Self::at_arguments(&mut word_iter) => {
+                        fields.push_arguments(state.args());
+                    }
+                    '$' => {
+                        let value = Self::expand_dollar(&mut word_iter, state)?;
+                        fields.push_quoted(&value);
+                    }
+                    '`' => {
+                        let value = Self::expand_backquoted(&mut word_iter, state)?;
+                        fields.push_quoted(&value);
+                    }
+                    _ => fields.push_quoted(&ch.to_string()),
                 },
-                WordState::RawBackSlash => {
-                    arg_type = WordState::Raw;
-                    buf.push(&text[i..=i]);
-                    start_index = i + 1;
+            }
+        }
+
+        Ok(fields.finish())
+    }
+
+    /// Decodes the escape following a backslash in `$'...'`: the C escapes such as
+    /// `\n` and `\t`, `\e` for escape, octal `\nnn`, hexadecimal `\xHH`, Unicode
+    /// `\uHHHH` and `\UHHHHHHHH`, and `\cX` for control characters. Unknown escapes are
+    /// kept with their backslash.
+    fn ansi_c_escape<I>(word_iter: &mut Peekable<I>, bytes: &mut Vec<u8>)
+    where
+        I: Iterator<Item = char>,
+    {
+        let push_char = |bytes: &mut Vec<u8>, ch: char| {
+            bytes.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes())
+        };
+
+        let ch = match word_iter.next() {
+            Some(ch) => ch,
+            None => return bytes.push(b'\\'),
+        };
+
+        match ch {
+            'a' => bytes.push(0x07),
+            'b' => bytes.push(0x08),
+            'e' | 'E' => bytes.push(0x1b),
+            'f' => bytes.push(0x0c),
+            'n' => bytes.push(b'\n'),
+            'r' => bytes.push(b'\r'),
+            't' => bytes.push(b'\t'),
+            'v' => bytes.push(0x0b),
+            '\\' | '\'' | '"' | '?' => bytes.push(ch as u8),
+            '0'..='7' => {
+                // Up to three digits, this one included.
+                let mut value = ch.to_digit(8).unwrap_or(0);
+                for _ in 0..2 {
+                    match word_iter.next_if(|c| c.is_digit(8)) {
+                        Some(digit) => value = value * 8 + digit.to_digit(8).unwrap_or(0),
+                        None => break,
+                    }
+                }
+                bytes.push(value as u8);
+            }
+            'x' => match Self::take_digits(word_iter, 16, 2) {
+                Some(value) => bytes.push(value as u8),
+                None => bytes.extend_from_slice(b"\\x"),
+            },
+            'u' | 'U' => {
+                let max = if ch == 'u' { 4 } else { 8 };
+                let digits: String = iter::from_fn(|| word_iter.next_if(char::is_ascii_hexdigit))
+                    .take(max)
+                    .collect();
+
+                // Without digits or for a code point that isn't a character, the escape
+                // is kept as it is.
+                match u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                {
+                    Some(c) => push_char(bytes, c),
+                    None => {
+                        bytes.push(b'\\');
+                        push_char(bytes, ch);
+                        bytes.extend_from_slice(digits.as_bytes());
+                    }
                 }
             }
+            'c' => match word_iter.next() {
+                Some('?') => bytes.push(0x7f),
+                Some(c) if c.is_ascii() => bytes.push(c as u8 & 0x1f),
+                Some(c) => {
+                    bytes.extend_from_slice(b"\\c");
+                    push_char(bytes, c);
+                }
+                None => bytes.extend_from_slice(b"\\c"),
+            },
+            _ => {
+                bytes.push(b'\\');
+                push_char(bytes, ch);
+            }
         }
+    }
+
+    /// Reads up to `max` digits in the given radix, `None` if there are none.
+    fn take_digits<I>(word_iter: &mut Peekable<I>, radix: u32, max: usize) -> Option<u32>
+    where
+        I: Iterator<Item = char>,
+    {
+        let mut value: Option<u32> = None;
+
+        for _ in 0..max {
+            match word_iter.next_if(|c| c.is_digit(radix)) {
+                Some(digit) => {
+                    let digit = digit.to_digit(radix).unwrap_or(0);
+                    value = Some(value.unwrap_or(0).wrapping_mul(radix) + digit);
+                }
+                None => break,
+            }
+        }
+
+        value
+    }
+
+    /// Expands the tilde prefix following a `~`, up to the next `/` (or `:` in an
+    /// assignment): `~` is the home directory, `~user` the one of `user`, `~+` and
+    /// `~-` are `PWD` and `OLDPWD`. A prefix with quotes or expansions, or which
+    /// doesn't resolve, is left as it is.
+    fn expand_tilde<I>(
+        word_iter: &mut Peekable<I>,
+        state: &State,
+        assignment: bool,
+    ) -> Option<String>
+    where
+        I: Iterator<Item = char> + Clone,
+    {
+        let prefix: String = word_iter
+            .clone()
+            .take_while(|c| *c != '/' && !(assignment && *c == ':'))
+            .collect();
+
+        if prefix.contains(['\'', '"', '\\', '$', '`']) {
+            return None;
+        }
+
+        let home = match prefix.as_str() {
+            "" => match state.vars().get("HOME") {
+                Some(home) => Some(home.to_string()),
+                None => Self::home_dir(None),
+            },
+            "+" => state.vars().get("PWD").map(String::from),
+            "-" => state.vars().get("OLDPWD").map(String::from),
+            user => Self::home_dir(Some(user)),
+        }?;
 
-        if arg_type == WordState::Raw {
-            buf.push(&text[start_index..]);
-            args.push(buf.join(""));
-            buf.clear();
-        } else if !buf.is_empty() {
-            args.push(buf.join(""));
+        for _ in prefix.chars() {
+            word_iter.next();
         }
 
-        args
+        Some(home)
+    }
+
+    /// Home directory of `user` from the passwd database, or of the current user.
+    fn home_dir(user: Option<&str>) -> Option<String> {
+        let entry = match user {
+            Some(user) => {
+                let name = CString::new(user).ok()?;
+                unsafe { libc::getpwnam(name.as_ptr()) }
+            }
+            None => unsafe { libc::getpwuid(libc::getuid()) },
+        };
+
+        if entry.is_null() {
+            return None;
+        }
+
+        let dir = unsafe { CStr::from_ptr((*entry).pw_dir) };
+        Some(dir.to_string_lossy().to_string())
+    }
+
+    /// Expands the body of a here-document whose delimiter is unquoted, like the inside
+    /// of double quotes except that double quotes are plain characters.
+    pub fn expand_here_doc(body: &str, state: &mut State) -> Result<String, String> {
+        let mut text = String::new();
+        let mut body_iter = body.chars().peekable();
+
+        while let Some(ch) = body_iter.next() {
+            match ch {
+                '\\' => match body_iter.peek() {
+                    Some('$') | Some('`') | Some('\\') => text.extend(body_iter.next()),
+                    Some('\n') => {
+                        body_iter.next();
+                    }
+                    _ => text.push(ch),
+                },
+                '$' => text.push_str(&Self::expand_dollar(&mut body_iter, state)?),
+                '`' => text.push_str(&Self::expand_backquoted(&mut body_iter, state)?),
+                _ => text.push(ch),
+            }
+        }
+
+        Ok(text)
+    }
+
+    /// Runs the command of a `` `...` `` substitution. Inside backquotes a backslash
+    /// only escapes `$`, `` ` `` and `\`.
+    fn expand_backquoted<I>(
+        word_iter: &mut Peekable<I>,
+        state: &mut State,
+    ) -> Result<String, String>
+    where
+        I: Iterator<Item = char>,
+    {
+        let inner = Self::read_backquoted(word_iter)
+            .map_err(|_| "unexpected EOF while looking for matching ``'")?;
+        let mut command = String::new();
+        let mut inner_iter = inner.chars().peekable();
+
+        while let Some(ch) = inner_iter.next() {
+            match (ch, inner_iter.peek()) {
+                ('\\', Some('$' | '`' | '\\')) => command.extend(inner_iter.next()),
+                _ => command.push(ch),
+            }
+        }
+
+        Ok(Operators::substitute(&command, state))
+    }
+
+    /// Expands what follows a `$`: a name, a special parameter or a `${...}` form. A
+    /// `$` followed by anything else is kept.
+    fn expand_dollar<I>(word_iter: &mut Peekable<I>, state: &mut State) -> Result<String, String>
+    where
+        I: Iterator<Item = char>,
+    {
+        match word_iter.peek().copied() {
+            Some('{') => {
+                word_iter.next();
+                match Self::read_group(word_iter, '{', '}') {
+                    Ok(inner) => Self::expand_braced(&inner, state),
+                    Err(partial) => Err(format!("${{{}: bad substitution", partial)),
+                }
+            }
+            Some('(') => {
+                word_iter.next();
+                match Self::read_group(word_iter, '(', ')') {
+                    // `$((expr))`
+                    Ok(inner) if inner.starts_with('(') && inner.ends_with(')') => {
+                        let expr = Self::expand(&inner[1..inner.len() - 1], state)?;
+                        Ok(arith::evaluate(&expr, state)?.to_string())
+                    }
+                    Ok(inner) => Ok(Operators::substitute(&inner, state)),
+                    Err(_) => Err(String::from(
+                        "unexpected EOF while looking for matching `)'",
+                    )),
+                }
+            }
+            Some(c) if c == '_' || c.is_ascii_alphabetic() => {
+                let mut name = String::new();
+                while let Some(c) = word_iter.next_if(|c| *c == '_' || c.is_ascii_alphanumeric()) {
+                    name.push(c);
+                }
+                Ok(Self::parameter(&name, state).unwrap_or_default())
+            }
+            Some(c) if SPECIAL_PARAMETERS.contains(&c) || c.is_ascii_digit() => {
+                word_iter.next();
+                Ok(Self::parameter(&c.to_string(), state).unwrap_or_default())
+            }
+            _ => Ok(String::from("$")),
+        }
+    }
+
+    /// Consumes the `@` or `{@}` after a `$` if it is there, for `"$@"`.
+    fn at_arguments(word_iter: &mut Peekable<Chars>) -> bool {
+        let mut ahead = word_iter.clone();
+        let found = match ahead.next() {
+            Some('@') => true,
+            Some('{') => ahead.next() == Some('@') && ahead.next() == Some('}'),
+            _ => false,
+        };
+
+        if found {
+            *word_iter = ahead;
+        }
+        found
+    }
+
+    /// The value of a variable or special parameter, `None` when it is unset.
+    fn parameter(name: &str, state: &State) -> Option<String> {
+        match name {
+            "?" => Some(state.status().to_string()),
+            "$" => Some(state.pid().to_string()),
+            "#" => Some(state.args().len().to_string()),
+            "@" => Some(state.args().join(" ")),
+            // `$*` joins the arguments with the first character of `IFS`.
+            "*" => {
+                let separator = match state.vars().get("IFS") {
+                    Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+                    None => String::from(" "),
+                };
+                Some(state.args().join(&separator))
+            }
+            "0" => env::args().next(),
+            _ if name.starts_with(|c: char| c.is_ascii_digit()) => name
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| state.args().get(n.checked_sub(1)?))
+                .cloned(),
+            _ => state.vars().get(name).map(str::to_string),
+        }
+    }
+
+    /// Expands the inside of `${...}`: `NAME`, `#NAME`, or `NAME` followed by one of
+    /// the operators.
+    fn expand_braced(inner: &str, state: &mut State) -> Result<String, String> {
+        let bad = || format!("${{{}}}: bad substitution", inner);
+
+        if let Some(name) = inner
+            .strip_prefix('#')
+            .filter(|name| Self::is_parameter(name))
+        {
+            // `${#@}` and `${#*}` count the positional parameters.
+            if name == "@" || name == "*" {
+                return Ok(state.args().len().to_string());
+            }
+            let value = Self::parameter(name, state).unwrap_or_default();
+            return Ok(value.chars().count().to_string());
+        }
+
+        let name_len = match inner.chars().next() {
+            Some(c) if SPECIAL_PARAMETERS.contains(&c) => 1,
+            // `${10}` and on, positional parameters with more than one digit.
+            Some(c) if c.is_ascii_digit() => inner
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(inner.len()),
+            _ => inner
+                .find(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+                .unwrap_or(inner.len()),
+        };
+        let (name, rest) = inner.split_at(name_len);
+
+        if name.is_empty() {
+            return Err(bad());
+        }
+
+        let value = Self::parameter(name, state);
+        if rest.is_empty() {
+            return Ok(value.unwrap_or_default());
+        }
+
+        let op = BRACE_OPERATORS
+            .iter()
+            .find(|op| rest.starts_with(*op))
+            .ok_or_else(bad)?;
+        let word = &rest[op.len()..];
+
+        // With a colon the operators treat an empty value like an unset one.
+        let missing = match &value {
+            None => true,
+            Some(value) => op.starts_with(':') && value.is_empty(),
+        };
+        let value = value.unwrap_or_default();
+
+        match *op {
+            ":-" | "-" if missing => Self::expand(word, state),
+            ":=" | "=" if missing => {
+                if !Variables::is_valid_name(name) {
+                    return Err(format!("${}: cannot assign in this way", name));
+                }
+                let word = Self::expand(word, state)?;
+                state.vars_mut().set(name, &word);
+                Ok(word)
+            }
+            ":?" | "?" if missing => {
+                let message = match Self::expand(word, state)? {
+                    message if message.is_empty() => String::from("parameter null or not set"),
+                    message => message,
+                };
+                // The command fails, and the rest of the input isn't run either.
+                state.set_jump(Some(Jump::Abort));
+                Err(format!("{}: {}", name, message))
+            }
+            ":+" | "+" if missing => Ok(String::new()),
+            ":+" | "+" => Self::expand(word, state),
+            "#" | "##" => {
+                let pattern = Pattern::new(&Self::expand_pattern(word, state)?);
+                Ok(pattern.remove_prefix(&value, *op == "##"))
+            }
+            "%" | "%%" => {
+                let pattern = Pattern::new(&Self::expand_pattern(word, state)?);
+                Ok(pattern.remove_suffix(&value, *op == "%%"))
+            }
+            "/" | "//" | "/#" | "/%" => {
+                let (pattern, replacement) = match Self::split_unquoted(word, '/') {
+                    Some((pattern, replacement)) => (pattern, replacement),
+                    None => (word, ""),
+                };
+                let pattern = Pattern::new(&Self::expand_pattern(pattern, state)?);
+                let replacement = Self::expand(replacement, state)?;
+
+                let anchor = op.chars().nth(1).filter(|c| *c == '#' || *c == '%');
+                Ok(pattern.replace(&value, &replacement, *op == "//", anchor))
+            }
+            _ => Ok(value),
+        }
+    }
+
+    fn is_parameter(name: &str) -> bool {
+        Variables::is_valid_name(name)
+            || (name.chars().count() == 1 && name.starts_with(SPECIAL_PARAMETERS))
+            || (!name.is_empty() && name.chars().all(|c| c.is_ascii_digit()))
+    }
+
+    /// Splits a word at the first `separator` that is not quoted or escaped.
+    fn split_unquoted(word: &str, separator: char) -> Option<(&str, &str)> {
+        let mut quote: Option<char> = None;
+        let mut escaped = false;
+
+        for (i, ch) in word.char_indices() {
+            match (ch, quote) {
+                _ if escaped => escaped = false,
+                ('\\', q) if q != Some('\'') => escaped = true,
+                ('\'' | '"', None) => quote = Some(ch),
+                (c, Some(q)) if c == q => quote = None,
+                (c, None) if c == separator => return Some((&word[..i], &word[i + 1..])),
+                _ => (),
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::ffi::OsStringExt;
+
+    use super::*;
+
+    fn braces(word: &str) -> Vec<String> {
+        WordParser::expand_braces(word)
+    }
+
+    #[test]
+    fn brace_lists() {
+        assert_eq!(braces("a{b,c}d"), ["abd", "acd"]);
+        assert_eq!(braces("x{,y}"), ["x", "xy"]);
+        assert_eq!(braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(braces("a{b,c{d,e}}f"), ["abf", "acdf", "acef"]);
+        assert_eq!(braces("{a,{b,{c,d}}}"), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn brace_ranges() {
+        assert_eq!(braces("{1..4}"), ["1", "2", "3", "4"]);
+        assert_eq!(braces("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(braces("{-1..1}"), ["-1", "0", "1"]);
+        assert_eq!(braces("{1..10..3}"), ["1", "4", "7", "10"]);
+        assert_eq!(braces("{10..1..4}"), ["10", "6", "2"]);
+        assert_eq!(braces("{1..5..-2}"), ["1", "3", "5"]);
+        assert_eq!(braces("{a..e..2}"), ["a", "c", "e"]);
+        assert_eq!(braces("{c..a}"), ["c", "b", "a"]);
+        assert_eq!(
+            braces("{Y..b}"),
+            ["Y", "Z", "\\[", "\\\\", "\\]", "\\^", "\\_", "\\`", "a", "b"]
+        );
+        assert_eq!(braces("x{1..2}{a..b}"), ["x1a", "x1b", "x2a", "x2b"]);
+    }
+
+    #[test]
+    fn padded_ranges() {
+        assert_eq!(braces("{01..03}"), ["01", "02", "03"]);
+        assert_eq!(braces("{8..010}"), ["008", "009", "010"]);
+        assert_eq!(braces("{-05..5..5}"), ["-05", "000", "005"]);
+        assert_eq!(braces("{00..4..2}"), ["00", "02", "04"]);
+    }
+
+    #[test]
+    fn literal_braces() {
+        assert_eq!(braces("{a}"), ["{a}"]);
+        assert_eq!(braces("{}"), ["{}"]);
+        assert_eq!(braces("{1..a}"), ["{1..a}"]);
+        assert_eq!(braces("{1..2..3..4}"), ["{1..2..3..4}"]);
+        assert_eq!(braces("{a,b"), ["{a,b"]);
+        assert_eq!(braces("\"{a,b}\""), ["\"{a,b}\""]);
+        assert_eq!(braces("\\{a,b}"), ["\\{a,b}"]);
+        assert_eq!(braces("${x,y}"), ["${x,y}"]);
+        assert_eq!(braces("{a,'b,c'}"), ["a", "'b,c'"]);
+        assert_eq!(braces("}{a,b}"), ["}a", "}b"]);
+    }
+
+    fn fields(word: &str, state: &mut State) -> Vec<String> {
+        WordParser::expand_fields(word, state).unwrap()
+    }
+
+    #[test]
+    fn missing_parameter_errors() {
+        let mut state = State::new();
+        state.vars_mut().set("empty", "");
+
+        assert_eq!(
+            WordParser::expand("${empty?unused}", &mut state),
+            Ok(String::new())
+        );
+        assert_eq!(state.jump(), None);
+        assert_eq!(
+            WordParser::expand("${empty:?is $empty}", &mut state),
+            Err(String::from("empty: is "))
+        );
+        assert_eq!(state.jump(), Some(Jump::Abort));
+        state.set_jump(None);
+
+        // The rest of the input isn't run.
+        Operators::run("x=1; echo ${nope?} || x=2; x=3", &mut state);
+        assert_eq!(state.vars().get("x"), Some("1"));
+        assert_eq!(state.status(), 1);
+        assert_eq!(state.jump(), None);
+    }
+
+    #[test]
+    fn quote_removal() {
+        let mut state = State::new();
+        state.vars_mut().set("x", "a  b");
+
+        assert_eq!(fields(r#"'$x'\ "$x""#, &mut state), ["$x a  b"]);
+        assert_eq!(fields(r#""\$x\q""#, &mut state), [r"$x\q"]);
+        assert_eq!(fields(r#""""#, &mut state), [""]);
+        assert_eq!(
+            WordParser::expand(r#"'a'"$x""#, &mut state),
+            Ok(String::from("aa  b"))
+        );
+    }
+
+    #[test]
+    fn ansi_c_quoting() {
+        let mut state = State::new();
+        let field = |word: &str, state: &mut State| -> String {
+            let mut fields = fields(word, state);
+            assert_eq!(fields.len(), 1, "{}", word);
+            fields.remove(0)
+        };
+
+        assert_eq!(field(r"$'a\tb\n'", &mut state), "a\tb\n");
+        assert_eq!(field(r"$'it\'s \\ \q'", &mut state), r"it's \ \q");
+        assert_eq!(field(r"$'\e[0m\a'", &mut state), "\x1b[0m\x07");
+
+        // Bytes that aren't UTF-8 are kept as they are until they reach a program.
+        let raw = field(r"$'\xff\xFEa'", &mut state);
+        assert_eq!(raw, bytes::encode(b"\xff\xfea"));
+        assert_eq!(bytes::decode(&raw), b"\xff\xfea");
+        assert_eq!(bytes::to_os_string(&raw).into_vec(), b"\xff\xfea");
+        assert_eq!(field(r"$'\xc3\xa9'", &mut state), "é");
+        assert_eq!(field(r"$'\x4g\xz'", &mut state), "\x04g\\xz");
+
+        // Octal takes up to three digits, a leading 0 included, and a NUL ends the
+        // string.
+        assert_eq!(field(r"$'\101\0101\7'", &mut state), "A\x081\x07");
+        assert_eq!(field(r"$'\377'", &mut state), bytes::encode(b"\xff"));
+        assert_eq!(field(r"$'a\0b'c", &mut state), "ac");
+
+        assert_eq!(field(r"$'\cA\ca\c[\c?'", &mut state), "\x01\x01\x1b\x7f");
+        assert_eq!(field(r"$'é\U0001F600☺!'", &mut state), "é😀☺!");
+        assert_eq!(field(r"$'\u\Uzz\uD800'", &mut state), r"\u\Uzz\uD800");
+
+        // `$"..."` is a double-quoted string, as there are no translations.
+        state.vars_mut().set("x", "a  b");
+        assert_eq!(field(r#"$"$x \$x""#, &mut state), "a  b $x");
+        assert_eq!(field(r#"$'a\tb'$"c""#, &mut state), "a\tbc");
+    }
+
+    #[test]
+    fn tildes() {
+        let mut state = State::new();
+        state.vars_mut().set("HOME", "/home/me");
+        state.vars_mut().set("PWD", "/work");
+        state.vars_mut().unset("OLDPWD");
+
+        assert_eq!(fields("~", &mut state), ["/home/me"]);
+        assert_eq!(fields("~/a/~", &mut state), ["/home/me/a/~"]);
+        assert_eq!(fields("~+/x", &mut state), ["/work/x"]);
+        assert_eq!(fields("~-", &mut state), ["~-"]);
+        state.vars_mut().set("OLDPWD", "/old");
+        assert_eq!(fields("~-/y", &mut state), ["/old/y"]);
+
+        let root = WordParser::home_dir(Some("root")).unwrap();
+        assert_eq!(fields("~root/z", &mut state), [format!("{}/z", root)]);
+        assert_eq!(fields("~no-such-user/z", &mut state), ["~no-such-user/z"]);
+
+        // Only an unquoted tilde at the start of the word is expanded.
+        assert_eq!(fields(r#""~""#, &mut state), ["~"]);
+        assert_eq!(fields("'~'/a", &mut state), ["~/a"]);
+        assert_eq!(fields(r"\~", &mut state), ["~"]);
+        assert_eq!(fields(r#"~"me""#, &mut state), ["~me"]);
+        assert_eq!(fields(r#"~"/a""#, &mut state), ["~/a"]);
+        assert_eq!(fields("a~", &mut state), ["a~"]);
+
+        // After a colon, a tilde is only expanded in assignments.
+        assert_eq!(fields("a:~/b", &mut state), ["a:~/b"]);
+        assert_eq!(
+            WordParser::expand_assignment("~/b:~+:x~", &mut state),
+            Ok(String::from("/home/me/b:/work:x~"))
+        );
+        assert_eq!(
+            fields("PATH=~/bin:~/sbin", &mut state),
+            ["PATH=/home/me/bin:/home/me/sbin"]
+        );
+    }
+
+    #[test]
+    fn field_splitting() {
+        let mut state = State::new();
+        state.vars_mut().set("x", " a  b ");
+        state.vars_mut().set("empty", "");
+
+        assert_eq!(fields("$x", &mut state), ["a", "b"]);
+        assert_eq!(fields("<$x>", &mut state), ["<", "a", "b", ">"]);
+        assert_eq!(fields(r#""$x""#, &mut state), [" a  b "]);
+        assert!(fields("$empty", &mut state).is_empty());
+        assert_eq!(fields(r#""$empty""#, &mut state), [""]);
+
+        state.vars_mut().set("IFS", ":");
+        state.vars_mut().set("y", "a::b");
+        assert_eq!(fields("$y", &mut state), ["a", "", "b"]);
+    }
+
+    #[test]
+    fn positional_parameters() {
+        let mut state = State::new();
+        state.set_args(vec![String::from("a b"), String::from("c")]);
+
+        assert_eq!(fields(r#""$@""#, &mut state), ["a b", "c"]);
+        assert_eq!(fields(r#"x"$@"y"#, &mut state), ["xa b", "cy"]);
+        assert_eq!(fields("$@", &mut state), ["a", "b", "c"]);
+        assert_eq!(fields(r#""$*""#, &mut state), ["a b c"]);
+        assert_eq!(fields("$#:${2}:${3-none}", &mut state), ["2:c:none"]);
+
+        state.set_args(Vec::new());
+        assert!(fields(r#""$@""#, &mut state).is_empty());
+        assert_eq!(fields(r#"x"$@""#, &mut state), ["x"]);
+    }
+
+    #[test]
+    fn positional_parameters_of_functions() {
+        let mut state = State::new();
+        state.set_args(vec![String::from("top")]);
+
+        Operators::run(
+            "f() { local v=$1; shift; r=$#:$v:$1; return 3; r=no; }; f a b c; s=$?",
+            &mut state,
+        );
+        assert_eq!(state.vars().get("r"), Some("2:a:b"));
+        assert_eq!(state.vars().get("s"), Some("3"));
+        assert_eq!(state.vars().get("v"), None);
+        assert_eq!(state.args(), ["top"]);
     }
 }
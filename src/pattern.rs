@@ -0,0 +1,409 @@
+/// Characters with a meaning in patterns, escaped with a backslash to be matched
+/// literally.
+const SPECIAL: [char; 5] = ['*', '?', '[', ']', '\\'];
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+    /// `[...]`, or `[!...]` when negated
+    Class {
+        negated: bool,
+        items: Vec<ClassItem>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    /// `[:alpha:]` and the other POSIX character classes
+    Named(String),
+}
+
+/// A shell pattern, as used by `case`, pathname expansion and the `${NAME#pattern}`
+/// family of parameter expansions.
+#[derive(Debug)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+    ignore_case: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            match chars[i] {
+                '\\' if i + 1 < chars.len() => {
+                    tokens.push(Token::Char(chars[i + 1]));
+                    i += 1;
+                }
+                '*' => {
+                    if tokens.last() != Some(&Token::Star) {
+                        tokens.push(Token::Star);
+                    }
+                }
+                '?' => tokens.push(Token::Any),
+                '[' => match Self::class(&chars[i + 1..]) {
+                    Some((token, len)) => {
+                        tokens.push(token);
+                        i += len;
+                    }
+                    None => tokens.push(Token::Char('[')),
+                },
+                ch => tokens.push(Token::Char(ch)),
+            }
+            i += 1;
+        }
+
+        Self {
+            tokens,
+            ignore_case: false,
+        }
+    }
+
+    /// Makes letters match regardless of their case, for `nocaseglob`.
+    pub fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    /// Reads a bracket expression following its `[`, returning it with the number of
+    /// characters it took. An unclosed bracket is a plain `[`.
+    fn class(chars: &[char]) -> Option<(Token, usize)> {
+        let mut i = 0;
+        let negated = matches!(chars.first(), Some('!') | Some('^'));
+        if negated {
+            i += 1;
+        }
+
+        let mut items = Vec::new();
+        let start = i;
+
+        loop {
+            let ch = *chars.get(i)?;
+
+            match ch {
+                // A `]` right after the opening bracket is part of the set.
+                ']' if i > start => return Some((Token::Class { negated, items }, i + 1)),
+                '[' if chars.get(i + 1) == Some(&':') => {
+                    let rest: String = chars[i + 2..].iter().collect();
+                    let end = rest.find(":]")?;
+                    items.push(ClassItem::Named(rest[..end].to_string()));
+                    i += 2 + rest[..end].chars().count() + 2;
+                    continue;
+                }
+                '\\' => {
+                    i += 1;
+                    items.push(ClassItem::Char(*chars.get(i)?));
+                }
+                _ => {
+                    if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|c| *c != ']')
+                    {
+                        items.push(ClassItem::Range(ch, chars[i + 2]));
+                        i += 2;
+                    } else {
+                        items.push(ClassItem::Char(ch));
+                    }
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// Escapes the special characters of `text` so it matches only itself.
+    pub fn escape(text: &str) -> String {
+        let mut escaped = String::new();
+
+        for ch in text.chars() {
+            if SPECIAL.contains(&ch) {
+                escaped.push('\\');
+            }
+            escaped.push(ch);
+        }
+
+        escaped
+    }
+
+    /// Whether `pattern` has unescaped special characters, so it can match more than
+    /// one string.
+    pub fn has_special(pattern: &str) -> bool {
+        let mut chars = pattern.chars();
+
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => {
+                    chars.next();
+                }
+                '*' | '?' | '[' => return true,
+                _ => (),
+            }
+        }
+
+        false
+    }
+
+    /// Removes the backslashes escaping characters of `pattern`.
+    pub fn unescape(pattern: &str) -> String {
+        let mut text = String::new();
+        let mut chars = pattern.chars();
+
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => text.extend(chars.next()),
+                ch => text.push(ch),
+            }
+        }
+
+        text
+    }
+
+    /// Whether the whole of `text` matches the pattern.
+    pub fn matches(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        self.matches_chars(&chars)
+    }
+
+    fn matches_chars(&self, text: &[char]) -> bool {
+        let tokens = &self.tokens;
+        let (mut t, mut p) = (0, 0);
+        // Where to resume after the last `*` if the rest fails to match.
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        while t < text.len() {
+            match tokens.get(p) {
+                Some(Token::Star) => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                }
+                Some(token) if self.matches_char(token, text[t]) => {
+                    p += 1;
+                    t += 1;
+                }
+                _ => match backtrack {
+                    Some((star, start)) => {
+                        p = star + 1;
+                        t = start + 1;
+                        backtrack = Some((star, start + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+
+        tokens[p..].iter().all(|token| *token == Token::Star)
+    }
+
+    fn matches_char(&self, token: &Token, ch: char) -> bool {
+        // With `ignore_case`, a character also matches as its other case.
+        let mut cases = vec![ch];
+        if self.ignore_case {
+            cases.extend(ch.to_lowercase().chain(ch.to_uppercase()));
+        }
+
+        match token {
+            Token::Char(c) => cases.contains(c),
+            Token::Any => true,
+            Token::Star => false,
+            Token::Class { negated, items } => {
+                let found = cases.iter().any(|ch| Self::class_contains(items, *ch));
+                found != *negated
+            }
+        }
+    }
+
+    fn class_contains(items: &[ClassItem], ch: char) -> bool {
+        items.iter().any(|item| match item {
+            ClassItem::Char(c) => *c == ch,
+            ClassItem::Range(from, to) => (*from..=*to).contains(&ch),
+            ClassItem::Named(name) => match name.as_str() {
+                "alpha" => ch.is_alphabetic(),
+                "digit" => ch.is_ascii_digit(),
+                "alnum" => ch.is_alphanumeric(),
+                "upper" => ch.is_uppercase(),
+                "lower" => ch.is_lowercase(),
+                "space" => ch.is_whitespace(),
+                "blank" => ch == ' ' || ch == '\t',
+                "punct" => ch.is_ascii_punctuation(),
+                "xdigit" => ch.is_ascii_hexdigit(),
+                "cntrl" => ch.is_control(),
+                "print" => !ch.is_control(),
+                "graph" => !ch.is_control() && !ch.is_whitespace(),
+                _ => false,
+            },
+        })
+    }
+
+    /// Removes the shortest or longest matching prefix (`${NAME#pattern}`,
+    /// `${NAME##pattern}`).
+    pub fn remove_prefix(&self, text: &str, longest: bool) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut ends: Vec<usize> = (0..=chars.len()).collect();
+        if longest {
+            ends.reverse();
+        }
+
+        match ends
+            .into_iter()
+            .find(|end| self.matches_chars(&chars[..*end]))
+        {
+            Some(end) => chars[end..].iter().collect(),
+            None => text.to_string(),
+        }
+    }
+
+    /// Removes the shortest or longest matching suffix (`${NAME%pattern}`,
+    /// `${NAME%%pattern}`).
+    pub fn remove_suffix(&self, text: &str, longest: bool) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut starts: Vec<usize> = (0..=chars.len()).collect();
+        if !longest {
+            starts.reverse();
+        }
+
+        match starts
+            .into_iter()
+            .find(|start| self.matches_chars(&chars[*start..]))
+        {
+            Some(start) => chars[..start].iter().collect(),
+            None => text.to_string(),
+        }
+    }
+
+    /// Replaces the longest matches of the pattern (`${NAME/pattern/replacement}`).
+    /// Only the first match is replaced unless `all` is set, `anchor` ties the match
+    /// to the start (`#`) or the end (`%`) of the text.
+    pub fn replace(
+        &self,
+        text: &str,
+        replacement: &str,
+        all: bool,
+        anchor: Option<char>,
+    ) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut result = String::new();
+        let mut i = 0;
+
+        while i <= chars.len() {
+            let found = match anchor {
+                Some('#') if i > 0 => None,
+                Some('%') => self.matches_chars(&chars[i..]).then_some(chars.len()),
+                _ => (i..=chars.len())
+                    .rev()
+                    .find(|end| self.matches_chars(&chars[i..*end])),
+            };
+
+            match found {
+                // An empty match would replace between every character.
+                Some(end) if end > i || anchor.is_some() => {
+                    result.push_str(replacement);
+                    i = end;
+
+                    if !all || anchor.is_some() {
+                        break;
+                    }
+                }
+                _ => {
+                    if let Some(ch) = chars.get(i) {
+                        result.push(*ch);
+                    }
+                    i += 1;
+                }
+            }
+        }
+
+        result.extend(chars.get(i..).unwrap_or(&[]));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).matches(text)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b", "aXbY"));
+        assert!(matches("?.?", "a.b"));
+        assert!(!matches("?", ""));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+    }
+
+    #[test]
+    fn bracket_classes() {
+        assert!(matches("[abc]", "b"));
+        assert!(!matches("[abc]", "d"));
+        assert!(matches("[a-c]x", "cx"));
+        assert!(matches("[!a-c]", "d"));
+        assert!(matches("[^a-c]", "d"));
+        assert!(!matches("[!a-c]", "a"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("[[:digit:]][[:alpha:]]", "1z"));
+        assert!(matches("[[:space:][:punct:]]", "."));
+        assert!(!matches("[[:upper:]]", "a"));
+        // An unclosed bracket is a plain character.
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn ignore_case() {
+        assert!(Pattern::new("*.TXT").ignore_case(true).matches("a.txt"));
+        assert!(Pattern::new("[A-C]").ignore_case(true).matches("b"));
+        assert!(!Pattern::new("*.TXT").matches("a.txt"));
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(Pattern::escape("a*[b]"), "a\\*\\[b\\]");
+        assert_eq!(Pattern::unescape("a\\*\\[b\\]"), "a*[b]");
+        assert!(Pattern::has_special("a[bc]"));
+        assert!(!Pattern::has_special("a\\*b"));
+    }
+
+    #[test]
+    fn prefix_and_suffix() {
+        let pattern = Pattern::new("*/");
+        assert_eq!(pattern.remove_prefix("a/b/c", false), "b/c");
+        assert_eq!(pattern.remove_prefix("a/b/c", true), "c");
+        assert_eq!(pattern.remove_prefix("abc", true), "abc");
+
+        let pattern = Pattern::new(".*");
+        assert_eq!(pattern.remove_suffix("a.tar.gz", false), "a.tar");
+        assert_eq!(pattern.remove_suffix("a.tar.gz", true), "a");
+        assert_eq!(pattern.remove_suffix("abc", false), "abc");
+    }
+
+    #[test]
+    fn replace() {
+        let pattern = Pattern::new("o*");
+        assert_eq!(pattern.replace("foo boo", "0", false, None), "f0");
+
+        let pattern = Pattern::new("o");
+        assert_eq!(pattern.replace("foo boo", "0", false, None), "f0o boo");
+        assert_eq!(pattern.replace("foo boo", "0", true, None), "f00 b00");
+        assert_eq!(pattern.replace("foo", "0", true, Some('#')), "foo");
+        assert_eq!(pattern.replace("oof", "0", true, Some('#')), "0of");
+        assert_eq!(pattern.replace("foo", "0", true, Some('%')), "fo0");
+        assert_eq!(pattern.replace("foo", "", true, None), "f");
+
+        // An empty pattern anchored at an end inserts the replacement there.
+        assert_eq!(Pattern::new("").replace("ab", "x", false, Some('#')), "xab");
+        assert_eq!(Pattern::new("").replace("ab", "x", false, Some('%')), "abx");
+    }
+}
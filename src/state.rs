@@ -1,25 +1,104 @@
-use std::env;
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, IsTerminal, Write},
+    rc::Rc,
+};
+
+use crate::{
+    ast::Command,
+    jobs::Jobs,
+    options::Options,
+    vars::{Variable, Variables},
+};
+
+/// Where execution goes instead of the next command, set by `break`, `continue` and
+/// `return`, when a foreground job is interrupted or by a fatal expansion error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jump {
+    /// Leaves this many enclosing loops.
+    Break(usize),
+    /// Goes to the next iteration of the loop this many levels out.
+    Continue(usize),
+    /// Leaves the function being run, set by `return`.
+    Return,
+    /// Abandons the rest of the input after Ctrl-C.
+    Interrupt,
+    /// Abandons the rest of the input after `${name:?message}` found no value.
+    Abort,
+}
+
+/// A function call in progress.
+struct Frame {
+    /// The positional parameters of the caller, given back on return.
+    caller_args: Vec<String>,
+    /// Variables made local by the function, with what they were before.
+    locals: Vec<(String, Option<Variable>)>,
+}
 
 pub struct State {
+    /// Process id of the shell, kept by its forked copies for `$$`.
+    pid: u32,
     pwd: String,
-    stdout: String,
-    stderr: String,
+    status: i32,
+    jobs: Jobs,
+    vars: Variables,
+    options: Options,
+    /// Status of the last command substitution, the status of a command made only of
+    /// assignments.
+    substitution_status: Option<i32>,
+    jump: Option<Jump>,
+    /// Number of loops the running command is in.
+    loop_depth: usize,
+    functions: HashMap<String, Rc<Command>>,
+    /// Positional parameters, `$1` and on.
+    args: Vec<String>,
+    frames: Vec<Frame>,
 }
 
 impl State {
     pub fn new() -> Self {
-        let pwd = match env::current_dir() {
-            Ok(path) => path.to_string_lossy().to_string(),
+        let cwd = match env::current_dir() {
+            Ok(path) => path,
             Err(err) => panic!("Error getting current directory: {}", err),
         };
 
+        let mut vars = Variables::from_env();
+
+        // An inherited `PWD` keeps the symbolic links the user went through, as long
+        // as it still names the current directory.
+        let pwd = match vars.get("PWD") {
+            Some(pwd)
+                if pwd.starts_with('/')
+                    && fs::canonicalize(pwd).ok() == fs::canonicalize(&cwd).ok() =>
+            {
+                pwd.to_string()
+            }
+            _ => cwd.to_string_lossy().to_string(),
+        };
+        vars.set("PWD", &pwd);
+        vars.export("PWD", true);
+
         Self {
+            pid: std::process::id(),
             pwd,
-            stdout: String::new(),
-            stderr: String::new(),
+            status: 0,
+            jobs: Jobs::default(),
+            vars,
+            options: Options::default(),
+            substitution_status: None,
+            jump: None,
+            loop_depth: 0,
+            functions: HashMap::new(),
+            args: Vec::new(),
+            frames: Vec::new(),
         }
     }
 
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
     pub fn pwd(&self) -> &str {
         &self.pwd
     }
@@ -28,26 +107,144 @@ impl State {
         self.pwd = pwd.to_string();
     }
 
-    pub fn flush_stdout(&mut self) -> String {
-        let output = self.stdout.clone();
-        self.stdout.clear();
+    /// Exit status of the last executed command, exposed to the user as `$?`.
+    pub fn status(&self) -> i32 {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: i32) {
+        self.status = status;
+    }
+
+    /// Records the status of a command substitution, which is also visible as `$?`
+    /// to the rest of the command.
+    pub fn set_substitution_status(&mut self, status: i32) {
+        self.status = status;
+        self.substitution_status = Some(status);
+    }
+
+    pub fn take_substitution_status(&mut self) -> Option<i32> {
+        self.substitution_status.take()
+    }
+
+    /// The pending jump. Lists stop running commands while one is set, until the
+    /// loop it targets clears it.
+    pub fn jump(&self) -> Option<Jump> {
+        self.jump
+    }
+
+    pub fn set_jump(&mut self, jump: Option<Jump>) {
+        self.jump = jump;
+    }
+
+    pub fn loop_depth(&self) -> usize {
+        self.loop_depth
+    }
+
+    pub fn set_loop_depth(&mut self, depth: usize) {
+        self.loop_depth = depth;
+    }
+
+    pub fn function(&self, name: &str) -> Option<Rc<Command>> {
+        self.functions.get(name).cloned()
+    }
+
+    pub fn set_function(&mut self, name: &str, body: Rc<Command>) {
+        self.functions.insert(name.to_string(), body);
+    }
+
+    pub fn unset_function(&mut self, name: &str) {
+        self.functions.remove(name);
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
 
-        output
+    /// Enters a function called with `args` as its positional parameters.
+    pub fn push_frame(&mut self, args: Vec<String>) {
+        self.frames.push(Frame {
+            caller_args: std::mem::replace(&mut self.args, args),
+            locals: Vec::new(),
+        });
     }
 
-    pub fn write_stdout(&mut self, text: &str) {
-        self.stdout.push_str(text);
+    /// Leaves the current function, giving the caller back its positional
+    /// parameters and the variables the function made local.
+    pub fn pop_frame(&mut self) {
+        if let Some(frame) = self.frames.pop() {
+            self.args = frame.caller_args;
+            for (name, var) in frame.locals.into_iter().rev() {
+                self.vars.restore(&name, var);
+            }
+        }
     }
 
-    pub fn flush_stderr(&mut self) -> String {
-        let error = self.stderr.clone();
-        self.stderr.clear();
+    pub fn in_function(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// Makes a variable local to the current function, so it gets its value back
+    /// when the function returns. Returns false outside of a function.
+    pub fn make_local(&mut self, name: &str) -> bool {
+        let Some(frame) = self.frames.last_mut() else {
+            return false;
+        };
+
+        if !frame.locals.iter().any(|(local, _)| local == name) {
+            frame
+                .locals
+                .push((name.to_string(), self.vars.get_variable(name)));
+        }
+        true
+    }
+
+    pub fn jobs(&self) -> &Jobs {
+        &self.jobs
+    }
 
-        error
+    pub fn jobs_mut(&mut self) -> &mut Jobs {
+        &mut self.jobs
     }
 
-    pub fn write_stderr(&mut self, text: &str) {
-        self.stderr.push_str(text);
+    pub fn vars(&self) -> &Variables {
+        &self.vars
+    }
+
+    pub fn vars_mut(&mut self) -> &mut Variables {
+        &mut self.vars
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+
+    /// Writes to the shell's own standard output. Bytes go through unchanged unless
+    /// they are drawn on a terminal, where invalid UTF-8 is replaced.
+    pub fn write_stdout(&mut self, bytes: &[u8]) {
+        Self::write_to(io::stdout(), bytes);
+    }
+
+    pub fn write_stderr(&mut self, bytes: &[u8]) {
+        Self::write_to(io::stderr(), bytes);
+    }
+
+    fn write_to<W: Write + IsTerminal>(mut stream: W, bytes: &[u8]) {
+        let _ = if stream.is_terminal() {
+            stream.write_all(String::from_utf8_lossy(bytes).as_bytes())
+        } else {
+            stream.write_all(bytes)
+        };
+
+        let _ = stream.flush();
     }
 }
 
@@ -0,0 +1,241 @@
+use std::{collections::BTreeMap, env, os::unix::ffi::OsStrExt};
+
+use crate::bytes;
+
+/// A shell variable. A variable can be exported before it gets a value, like after
+/// `export FOO`, it is only passed to programs once it has one.
+#[derive(Debug, Clone)]
+pub struct Variable {
+    value: Option<String>,
+    exported: bool,
+}
+
+/// Variables of the shell, initialized from the environment it was started with.
+#[derive(Debug, Clone)]
+pub struct Variables {
+    table: BTreeMap<String, Variable>,
+}
+
+impl Variables {
+    /// Names and values that aren't valid UTF-8 are kept as the bytes they are, to be
+    /// passed on unchanged to programs.
+    pub fn from_env() -> Self {
+        Self {
+            table: env::vars_os()
+                .map(|(name, value)| {
+                    let var = Variable {
+                        value: Some(bytes::encode(value.as_bytes())),
+                        exported: true,
+                    };
+                    (bytes::encode(name.as_bytes()), var)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.table.get(name).and_then(|var| var.value.as_deref())
+    }
+
+    /// Assigns a value, an exported variable stays exported.
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self.table.get_mut(name) {
+            Some(var) => var.value = Some(value.to_string()),
+            None => {
+                self.table.insert(
+                    name.to_string(),
+                    Variable {
+                        value: Some(value.to_string()),
+                        exported: false,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Marks a variable for export to programs started by the shell, or removes the
+    /// mark.
+    pub fn export(&mut self, name: &str, exported: bool) {
+        match self.table.get_mut(name) {
+            Some(var) => var.exported = exported,
+            None if exported => {
+                self.table.insert(
+                    name.to_string(),
+                    Variable {
+                        value: None,
+                        exported,
+                    },
+                );
+            }
+            None => (),
+        }
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        self.table.remove(name);
+    }
+
+    /// A copy of the variable, to be put back with [`Variables::restore`].
+    pub fn get_variable(&self, name: &str) -> Option<Variable> {
+        self.table.get(name).cloned()
+    }
+
+    /// Puts back a variable saved by [`Variables::get_variable`], or removes it if
+    /// there was none. Used to undo the assignments made for a single builtin.
+    pub fn restore(&mut self, name: &str, var: Option<Variable>) {
+        match var {
+            Some(var) => self.table.insert(name.to_string(), var),
+            None => self.table.remove(name),
+        };
+    }
+
+    /// The environment passed to programs.
+    pub fn environment(&self) -> Vec<(String, String)> {
+        self.table
+            .iter()
+            .filter(|(_, var)| var.exported)
+            .filter_map(|(name, var)| Some((name.clone(), var.value.clone()?)))
+            .collect()
+    }
+
+    /// `export -p` listing, in a form that can be read back by the shell.
+    pub fn exports(&self) -> String {
+        self.table
+            .iter()
+            .filter(|(_, var)| var.exported)
+            .map(|(name, var)| match &var.value {
+                Some(value) => format!("declare -x {}=\"{}\"\n", name, escape(value)),
+                None => format!("declare -x {}\n", name),
+            })
+            .collect()
+    }
+
+    /// `set` listing of every variable with a value.
+    pub fn list(&self) -> String {
+        self.table
+            .iter()
+            .filter_map(|(name, var)| Some(format!("{}={}\n", name, quote(var.value.as_ref()?))))
+            .collect()
+    }
+
+    /// A name made of letters, digits and underscores, not starting with a digit.
+    pub fn is_valid_name(name: &str) -> bool {
+        let mut chars = name.chars();
+
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+}
+
+/// Escapes the characters that are special inside double quotes.
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+
+    for ch in value.chars() {
+        if matches!(ch, '"' | '\\' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+
+    escaped
+}
+
+/// Single quotes a value when it contains anything but plain word characters.
+fn quote(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:,+@%=".contains(c);
+
+    if !value.is_empty() && value.chars().all(plain) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty() -> Variables {
+        Variables {
+            table: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn assignment() {
+        let mut vars = empty();
+        assert_eq!(vars.get("x"), None);
+
+        vars.set("x", "1");
+        vars.set("x", "a b");
+        assert_eq!(vars.get("x"), Some("a b"));
+        assert_eq!(vars.list(), "x='a b'\n");
+        assert!(vars.environment().is_empty());
+
+        assert!(Variables::is_valid_name("_a1"));
+        assert!(!Variables::is_valid_name("1a"));
+        assert!(!Variables::is_valid_name("a-b"));
+        assert!(!Variables::is_valid_name(""));
+    }
+
+    #[test]
+    fn export() {
+        let mut vars = empty();
+        vars.set("x", "1");
+        vars.export("x", true);
+        vars.set("x", "2");
+        assert_eq!(vars.environment(), [("x".to_string(), "2".to_string())]);
+
+        // Exported without a value, it is only passed on once it gets one.
+        vars.export("y", true);
+        assert_eq!(vars.get("y"), None);
+        assert_eq!(vars.exports(), "declare -x x=\"2\"\ndeclare -x y\n");
+        assert_eq!(vars.environment().len(), 1);
+        vars.set("y", "say \"$hi\"");
+        assert_eq!(
+            vars.exports(),
+            "declare -x x=\"2\"\ndeclare -x y=\"say \\\"\\$hi\\\"\"\n"
+        );
+
+        vars.export("x", false);
+        assert_eq!(vars.get("x"), Some("2"));
+        assert_eq!(
+            vars.environment(),
+            [("y".to_string(), "say \"$hi\"".to_string())]
+        );
+    }
+
+    #[test]
+    fn unset() {
+        let mut vars = empty();
+        vars.set("x", "1");
+        vars.export("x", true);
+        vars.unset("x");
+        vars.unset("nope");
+        assert_eq!(vars.get("x"), None);
+        assert!(vars.environment().is_empty());
+
+        // A new assignment doesn't bring back the export.
+        vars.set("x", "2");
+        assert!(vars.environment().is_empty());
+    }
+
+    #[test]
+    fn restore() {
+        let mut vars = empty();
+        vars.set("x", "1");
+
+        let saved = [("x", vars.get_variable("x")), ("y", vars.get_variable("y"))];
+        vars.set("x", "2");
+        vars.export("x", true);
+        vars.set("y", "3");
+
+        for (name, var) in saved {
+            vars.restore(name, var);
+        }
+        assert_eq!(vars.get("x"), Some("1"));
+        assert_eq!(vars.get("y"), None);
+        assert!(vars.environment().is_empty());
+    }
+}